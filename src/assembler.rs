@@ -0,0 +1,247 @@
+use std::fmt;
+
+// Error with the source line it occurred on (starting at 1)
+#[derive(Debug, PartialEq)]
+pub struct AssemblerError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// Translate source in the disassembler's syntax into a ROM, one instruction per line and
+// everything after ';' ignored
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssemblerError> {
+    let mut rom = vec![];
+
+    for (index, line) in source.lines().enumerate() {
+        let code = line.split(';').next().unwrap_or_default().trim();
+
+        if code.is_empty() {
+            continue;
+        }
+
+        let error = |message: String| AssemblerError {
+            line: index + 1,
+            message,
+        };
+
+        let (mnemonic, operands) = match code.find(char::is_whitespace) {
+            Some(position) => (&code[..position], code[position..].trim()),
+            None => (code, ""),
+        };
+        let operands: Vec<&str> = if operands.is_empty() {
+            vec![]
+        } else {
+            operands.split(',').map(str::trim).collect()
+        };
+
+        if mnemonic.eq_ignore_ascii_case("DB") {
+            let byte = match operands.as_slice() {
+                [value] => parse_value(value, 0xff).map_err(error)?,
+                _ => return Err(error(String::from("DB takes one byte"))),
+            };
+            rom.push(byte as u8);
+        } else {
+            let opcode = encode(&mnemonic.to_uppercase(), &operands).map_err(error)?;
+            rom.push((opcode >> 8) as u8);
+            rom.push((opcode & 0xff) as u8);
+        }
+    }
+
+    Ok(rom)
+}
+
+// Encode a single instruction
+fn encode(mnemonic: &str, operands: &[&str]) -> Result<u16, String> {
+    let operands: Vec<Operand> = operands
+        .iter()
+        .map(|operand| parse_operand(operand))
+        .collect::<Result<_, _>>()?;
+
+    use Operand::*;
+
+    let opcode = match (mnemonic, operands.as_slice()) {
+        ("CLS", []) => 0x00E0,
+        ("RET", []) => 0x00EE,
+        ("SCR", []) => 0x00FB,
+        ("SCL", []) => 0x00FC,
+        ("LOW", []) => 0x00FE,
+        ("HIGH", []) => 0x00FF,
+        ("AUDIO", []) => 0xF002,
+        ("SCD", [Value(n)]) => {
+            if *n > 0xf {
+                return Err(format!("Scroll distance {} does not fit into a nibble", n));
+            }
+            0x00C0 | n
+        }
+        ("PLANE", [Value(planes)]) => {
+            if *planes > 3 {
+                return Err(format!("Planes {} are not one of 0 to 3", planes));
+            }
+            0xF001 | planes << 8
+        }
+        ("SYS", [Value(a)]) => address(*a)?,
+        ("JP", [Value(a)]) => 0x1000 | address(*a)?,
+        ("JP", [Register(0), Value(a)]) => 0xB000 | address(*a)?,
+        ("CALL", [Value(a)]) => 0x2000 | address(*a)?,
+        ("SE", [Register(x), Value(b)]) => 0x3000 | x << 8 | byte(*b)?,
+        ("SNE", [Register(x), Value(b)]) => 0x4000 | x << 8 | byte(*b)?,
+        ("SE", [Register(x), Register(y)]) => 0x5000 | x << 8 | y << 4,
+        ("LD", [Register(x), Value(b)]) => 0x6000 | x << 8 | byte(*b)?,
+        ("ADD", [Register(x), Value(b)]) => 0x7000 | x << 8 | byte(*b)?,
+        ("LD", [Register(x), Register(y)]) => 0x8000 | x << 8 | y << 4,
+        ("OR", [Register(x), Register(y)]) => 0x8001 | x << 8 | y << 4,
+        ("AND", [Register(x), Register(y)]) => 0x8002 | x << 8 | y << 4,
+        ("XOR", [Register(x), Register(y)]) => 0x8003 | x << 8 | y << 4,
+        ("ADD", [Register(x), Register(y)]) => 0x8004 | x << 8 | y << 4,
+        ("SUB", [Register(x), Register(y)]) => 0x8005 | x << 8 | y << 4,
+        ("SHR", [Register(x), Register(y)]) => 0x8006 | x << 8 | y << 4,
+        ("SUBN", [Register(x), Register(y)]) => 0x8007 | x << 8 | y << 4,
+        ("SHL", [Register(x), Register(y)]) => 0x800E | x << 8 | y << 4,
+        ("SNE", [Register(x), Register(y)]) => 0x9000 | x << 8 | y << 4,
+        ("LD", [Index, Value(a)]) => 0xA000 | address(*a)?,
+        ("RND", [Register(x), Value(b)]) => 0xC000 | x << 8 | byte(*b)?,
+        ("DRW", [Register(x), Register(y), Value(n)]) => {
+            if *n > 0xf {
+                return Err(format!("Sprite height {} does not fit into a nibble", n));
+            }
+            0xD000 | x << 8 | y << 4 | n
+        }
+        ("SKP", [Register(x)]) => 0xE09E | x << 8,
+        ("SKNP", [Register(x)]) => 0xE0A1 | x << 8,
+        ("LD", [Register(x), DelayTimer]) => 0xF007 | x << 8,
+        ("LD", [Register(x), Key]) => 0xF00A | x << 8,
+        ("LD", [DelayTimer, Register(x)]) => 0xF015 | x << 8,
+        ("LD", [SoundTimer, Register(x)]) => 0xF018 | x << 8,
+        ("ADD", [Index, Register(x)]) => 0xF01E | x << 8,
+        ("LD", [Font, Register(x)]) => 0xF029 | x << 8,
+        ("LD", [LargeFont, Register(x)]) => 0xF030 | x << 8,
+        ("LD", [Bcd, Register(x)]) => 0xF033 | x << 8,
+        ("LD", [Pitch, Register(x)]) => 0xF03A | x << 8,
+        ("LD", [IndexMemory, Register(x)]) => 0xF055 | x << 8,
+        ("LD", [Register(x), IndexMemory]) => 0xF065 | x << 8,
+        ("LD", [Index, Long]) => 0xF000,
+        ("LD", [Flags, Register(x)]) => 0xF075 | x << 8,
+        ("LD", [Register(x), Flags]) => 0xF085 | x << 8,
+        ("DW", [Value(word)]) => *word,
+        _ => return Err(format!("Invalid instruction: {} {:?}", mnemonic, operands)),
+    };
+
+    Ok(opcode)
+}
+
+#[derive(Debug)]
+enum Operand {
+    Register(u16),
+    Value(u16),
+    Index,
+    IndexMemory,
+    DelayTimer,
+    SoundTimer,
+    Key,
+    Font,
+    LargeFont,
+    Bcd,
+    Flags,
+    Long,
+    Pitch,
+}
+
+// Parse a register, special operand or number
+fn parse_operand(operand: &str) -> Result<Operand, String> {
+    let upper = operand.to_uppercase();
+
+    let parsed = match upper.as_str() {
+        "I" => Operand::Index,
+        "[I]" => Operand::IndexMemory,
+        "DT" => Operand::DelayTimer,
+        "ST" => Operand::SoundTimer,
+        "K" => Operand::Key,
+        "F" => Operand::Font,
+        "HF" => Operand::LargeFont,
+        "B" => Operand::Bcd,
+        "R" => Operand::Flags,
+        "LONG" => Operand::Long,
+        "PITCH" => Operand::Pitch,
+        _ if upper.len() == 2 && upper.starts_with('V') => {
+            match u16::from_str_radix(&upper[1..], 16) {
+                Ok(register) => Operand::Register(register),
+                Err(_) => return Err(format!("Invalid register: {}", operand)),
+            }
+        }
+        _ => Operand::Value(parse_value(operand, 0xffff)?),
+    };
+
+    Ok(parsed)
+}
+
+// Parse a decimal or 0x-prefixed hexadecimal number up to a maximum
+fn parse_value(value: &str, maximum: u16) -> Result<u16, String> {
+    let parsed = if value.starts_with("0x") || value.starts_with("0X") {
+        u16::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse()
+    };
+
+    match parsed {
+        Ok(number) if number <= maximum => Ok(number),
+        _ => Err(format!("Invalid number: {}", value)),
+    }
+}
+
+// Check a value fits into an address
+fn address(value: u16) -> Result<u16, String> {
+    if value > 0xfff {
+        return Err(format!("Address {:#X} does not fit into 12 bits", value));
+    }
+    Ok(value)
+}
+
+// Check a value fits into a byte
+fn byte(value: u16) -> Result<u16, String> {
+    if value > 0xff {
+        return Err(format!("Value {:#X} does not fit into a byte", value));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::disassemble;
+
+    #[test]
+    fn test_assemble_instructions() {
+        let rom = assemble("CLS\nLD VA, 0x02 ; comment\n\nDRW V1, V2, 5\nLD [I], VF").unwrap();
+        assert_eq!(rom, vec![0x00, 0xE0, 0x6A, 0x02, 0xD1, 0x25, 0xFF, 0x55]);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(assemble("CLS\nLD VA, 0x100").unwrap_err().line, 2);
+        assert!(assemble("JP 0x1000").is_err());
+        assert!(assemble("NOP").is_err());
+        assert!(assemble("LD VG, 1").is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let rom: Vec<u8> = vec![
+            0x00, 0xE0, 0x00, 0xEE, 0x00, 0xC4, 0x00, 0xFB, 0x00, 0xFC, 0x00, 0xFE, 0x00, 0xFF,
+            0x01, 0x23, 0x12, 0x34, 0x23, 0x45, 0x31, 0x22, 0x41, 0x22, 0x51, 0x20, 0x61, 0x22,
+            0x71, 0x22, 0x81, 0x20, 0x81, 0x21, 0x81, 0x22, 0x81, 0x23, 0x81, 0x24, 0x81, 0x25,
+            0x81, 0x26, 0x81, 0x27, 0x81, 0x2E, 0x91, 0x20, 0xA1, 0x23, 0xB1, 0x23, 0xC1, 0x22,
+            0xD1, 0x2F, 0xE1, 0x9E, 0xE1, 0xA1, 0xF0, 0x00, 0xF3, 0x01, 0xF0, 0x02, 0xF1, 0x07,
+            0xF1, 0x0A, 0xF1, 0x15, 0xF1, 0x18, 0xF1, 0x1E, 0xF1, 0x29, 0xF1, 0x30, 0xF1, 0x33,
+            0xF1, 0x3A, 0xF1, 0x55, 0xF1, 0x65, 0xF1, 0x75, 0xF1, 0x85, 0xFF, 0xFF, 0x42,
+        ];
+
+        let source = disassemble(&rom, 0x200);
+        assert_eq!(assemble(&source).unwrap(), rom);
+    }
+}
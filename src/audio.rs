@@ -0,0 +1,293 @@
+use hound::{SampleFormat, WavSpec, WavWriter};
+use log::{info, warn};
+use rodio::buffer::SamplesBuffer;
+use rodio::source::{SineWave, Source};
+use rodio::{Device, Sink};
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+// Default sine beep frequency in Hz
+pub const BEEP_FREQ: u32 = 440;
+
+// Playback rate of one bit of an audio pattern in Hz (XO-CHIP default pitch)
+pub const PATTERN_RATE: u32 = 4_000;
+
+// Sample rate of recorded audio and the number of samples rendered per emulated frame
+const RECORDING_SAMPLE_RATE: u32 = 44_100;
+const RECORDING_SAMPLES_PER_FRAME: u32 = RECORDING_SAMPLE_RATE / 60;
+
+// Peak amplitude of recorded audio, leaving some headroom
+const RECORDING_AMPLITUDE: f32 = 0.5;
+
+// Sound output used by the periphery
+pub trait Audio {
+    // Start playing sound
+    fn play(&mut self);
+
+    // Stop playing sound
+    fn stop(&mut self);
+
+    // Experimental: replace the beep with two 1-bit patterns of 128 samples each, one per
+    // channel, panned from -1.0 (left only) to 1.0 (right only). XO-CHIP audio is mono, this
+    // exists so stereo experiments do not require changing the trait later. Backends without
+    // stereo output mix both channels down to mono.
+    fn play_stereo_patterns(&mut self, left: &[u8; 16], right: &[u8; 16], pan: f32);
+
+    // Replace the beep with a 1-bit pattern of 128 samples like XO-CHIP, playing rate samples
+    // per second
+    fn play_pattern(&mut self, pattern: &[u8; 16], rate: u32);
+
+    // Play the beep at another frequency, replacing any pattern
+    fn set_beep_frequency(&mut self, hz: u32);
+
+    // Called once per emulated frame, realtime backends have nothing to do here
+    fn end_frame(&mut self) {}
+}
+
+// Audio backend playing through the default rodio output device
+pub struct RodioAudio {
+    device: Device,
+    sink: Sink,
+}
+
+impl RodioAudio {
+    // Open the default output device with a paused beep, None if no device is available
+    pub fn new() -> Option<RodioAudio> {
+        let device = match rodio::default_output_device() {
+            Some(device) => device,
+            None => {
+                warn!("Unable to initialize default audio device, sound is disabled!");
+                return None;
+            }
+        };
+
+        info!("Using audio device {}", device.name());
+
+        let sink = Sink::new(&device);
+        sink.pause();
+        sink.append(SineWave::new(BEEP_FREQ));
+
+        Some(RodioAudio { device, sink })
+    }
+
+    // Replace the sound being played. Sources queue up behind the endless beep, so a fresh
+    // sink keeping the play state takes over.
+    fn replace_source<S>(&mut self, source: S)
+    where
+        S: Source<Item = f32> + Send + 'static,
+    {
+        let paused = self.sink.is_paused();
+        let sink = Sink::new(&self.device);
+
+        if paused {
+            sink.pause();
+        }
+
+        sink.append(source);
+
+        self.sink.stop();
+        self.sink = sink;
+    }
+}
+
+impl Audio for RodioAudio {
+    fn play(&mut self) {
+        self.sink.play();
+    }
+
+    fn stop(&mut self) {
+        self.sink.pause();
+    }
+
+    fn play_stereo_patterns(&mut self, left: &[u8; 16], right: &[u8; 16], pan: f32) {
+        let samples = mix_to_mono(left, right, pan);
+        self.replace_source(SamplesBuffer::new(1, PATTERN_RATE, samples).repeat_infinite());
+    }
+
+    fn play_pattern(&mut self, pattern: &[u8; 16], rate: u32) {
+        let samples = mix_to_mono(pattern, pattern, 0.0);
+        self.replace_source(SamplesBuffer::new(1, rate, samples).repeat_infinite());
+    }
+
+    fn set_beep_frequency(&mut self, hz: u32) {
+        self.replace_source(SineWave::new(hz));
+    }
+}
+
+impl Drop for RodioAudio {
+    // Stop the sink explicitly instead of relying on the output thread noticing it is gone
+    fn drop(&mut self) {
+        self.sink.stop();
+    }
+}
+
+// Audio backend rendering the sound of every emulated frame into a WAV file, so the recording
+// follows the sound timer exactly regardless of wall-clock time
+pub struct WavRecorder {
+    writer: Option<WavWriter<BufWriter<File>>>,
+    playing: bool,
+
+    // Pattern replacing the beep and its samples per second
+    pattern: Option<Vec<f32>>,
+    pattern_rate: u32,
+    beep_frequency: u32,
+
+    // Samples rendered so far, keeps waveforms continuous across frames
+    position: u64,
+}
+
+impl WavRecorder {
+    // Create the WAV file, it is finalized once the recorder is dropped
+    pub fn create(path: &Path) -> Result<WavRecorder, hound::Error> {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: RECORDING_SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+
+        Ok(WavRecorder {
+            writer: Some(WavWriter::create(path, spec)?),
+            playing: false,
+            pattern: None,
+            pattern_rate: PATTERN_RATE,
+            beep_frequency: BEEP_FREQ,
+            position: 0,
+        })
+    }
+
+    // Get the sample at a position between -1.0 and 1.0
+    fn sample(&self, position: u64) -> f32 {
+        if !self.playing {
+            return 0.0;
+        }
+
+        let time = position as f32 / RECORDING_SAMPLE_RATE as f32;
+
+        match &self.pattern {
+            Some(pattern) => {
+                let index =
+                    position * u64::from(self.pattern_rate) / u64::from(RECORDING_SAMPLE_RATE);
+                pattern[index as usize % pattern.len()]
+            }
+            None => (2.0 * PI * self.beep_frequency as f32 * time).sin(),
+        }
+    }
+}
+
+impl Audio for WavRecorder {
+    fn play(&mut self) {
+        self.playing = true;
+    }
+
+    fn stop(&mut self) {
+        self.playing = false;
+    }
+
+    fn play_stereo_patterns(&mut self, left: &[u8; 16], right: &[u8; 16], pan: f32) {
+        self.pattern = Some(mix_to_mono(left, right, pan));
+        self.pattern_rate = PATTERN_RATE;
+    }
+
+    fn play_pattern(&mut self, pattern: &[u8; 16], rate: u32) {
+        self.pattern = Some(mix_to_mono(pattern, pattern, 0.0));
+        self.pattern_rate = rate;
+    }
+
+    fn set_beep_frequency(&mut self, hz: u32) {
+        self.pattern = None;
+        self.beep_frequency = hz;
+    }
+
+    fn end_frame(&mut self) {
+        let samples: Vec<i16> = (0..u64::from(RECORDING_SAMPLES_PER_FRAME))
+            .map(|offset| {
+                let sample = self.sample(self.position + offset) * RECORDING_AMPLITUDE;
+                (sample * f32::from(i16::MAX)) as i16
+            })
+            .collect();
+        self.position += u64::from(RECORDING_SAMPLES_PER_FRAME);
+
+        let writer = match self.writer.as_mut() {
+            Some(writer) => writer,
+            None => return,
+        };
+
+        for sample in samples {
+            if let Err(e) = writer.write_sample(sample) {
+                warn!("Unable to record audio, stopping recording: {}", e);
+                self.writer = None;
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for WavRecorder {
+    // Write the final WAV header, which needs the total number of samples
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            if let Err(e) = writer.finalize() {
+                warn!("Unable to finalize audio recording: {}", e);
+            }
+        }
+    }
+}
+
+// Mix two 1-bit patterns into mono samples weighted by the pan position
+fn mix_to_mono(left: &[u8; 16], right: &[u8; 16], pan: f32) -> Vec<f32> {
+    let pan = pan.clamp(-1.0, 1.0);
+    let left_weight = (1.0 - pan) / 2.0;
+    let right_weight = (1.0 + pan) / 2.0;
+
+    let bit = |pattern: &[u8; 16], index: usize| {
+        if pattern[index / 8] & (0x80 >> (index % 8)) != 0 {
+            1.0
+        } else {
+            -1.0
+        }
+    };
+
+    (0..128)
+        .map(|index| bit(left, index) * left_weight + bit(right, index) * right_weight)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mix_to_mono() {
+        let left = [0xff; 16];
+        let right = [0x00; 16];
+
+        assert!(mix_to_mono(&left, &right, -1.0).iter().all(|s| *s == 1.0));
+        assert!(mix_to_mono(&left, &right, 1.0).iter().all(|s| *s == -1.0));
+        assert!(mix_to_mono(&left, &right, 0.0).iter().all(|s| *s == 0.0));
+        assert_eq!(mix_to_mono(&left, &right, 0.0).len(), 128);
+    }
+
+    #[test]
+    fn test_wav_recorder_follows_frames() {
+        let path = std::env::temp_dir().join("chirpy-test-recording.wav");
+
+        let mut recorder = WavRecorder::create(&path).unwrap();
+        recorder.play();
+        recorder.end_frame();
+        recorder.stop();
+        recorder.end_frame();
+        drop(recorder);
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        let samples: Vec<i16> = reader.samples().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        let frame = RECORDING_SAMPLES_PER_FRAME as usize;
+        assert_eq!(samples.len(), 2 * frame);
+        assert!(samples[..frame].iter().any(|sample| *sample > 0));
+        assert!(samples[frame..].iter().all(|sample| *sample == 0));
+    }
+}
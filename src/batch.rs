@@ -0,0 +1,80 @@
+use crate::system::System;
+
+use log::info;
+use std::any::Any;
+use std::ffi::OsStr;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+// Run every .ch8 ROM in a directory headless for a number of cycles and print a summary,
+// returns whether all ROMs ran without crashing
+pub fn run_batch<F>(directory: &Path, cycles: u64, load_address: usize, configure: F) -> bool
+where
+    F: Fn(&mut System),
+{
+    let mut roms: Vec<PathBuf> = fs::read_dir(directory)
+        .unwrap_or_else(|e| panic!("{}", e))
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension() == Some(OsStr::new("ch8")))
+        .collect();
+    roms.sort();
+
+    // Crashes are reported in the summary, keep the default panic output out of it
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut crashed = 0;
+
+    for rom in &roms {
+        info!("Running {} for {} cycles", rom.display(), cycles);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let buffer = fs::read(rom).unwrap_or_else(|e| panic!("{}", e));
+            let mut system = System::headless();
+            configure(&mut system);
+            if let Err(e) = system.copy_buffer_to_memory(buffer, load_address) {
+                return Err(e.to_string());
+            }
+            system.run_cycles(cycles);
+
+            match system.error() {
+                Some(e) => Err(e.to_string()),
+                None => Ok(system.frame_count()),
+            }
+        }));
+
+        match result {
+            Ok(Ok(frames)) => println!("ok       {} ({} frames)", rom.display(), frames),
+            Ok(Err(message)) => {
+                crashed += 1;
+                println!("crashed  {}: {}", rom.display(), message);
+            }
+            Err(payload) => {
+                crashed += 1;
+                println!("crashed  {}: {}", rom.display(), panic_message(&*payload));
+            }
+        }
+    }
+
+    let _ = panic::take_hook();
+
+    println!(
+        "{} of {} ROMs ran clean, {} crashed",
+        roms.len() - crashed,
+        roms.len(),
+        crashed
+    );
+
+    crashed == 0
+}
+
+// Extract the message of a caught panic
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else if let Some(message) = payload.downcast_ref::<&str>() {
+        String::from(*message)
+    } else {
+        String::from("unknown error")
+    }
+}
@@ -37,7 +37,14 @@ pub fn to_byte(b: u16) -> u8 {
 
 #[inline]
 pub fn to_usize(b: u16) -> usize {
-    b.try_into().unwrap()
+    usize::from(b)
+}
+
+// Swap the bytes of every opcode pair in place, a trailing odd byte is left untouched
+pub fn swap_byte_order(buffer: &mut [u8]) {
+    for pair in buffer.chunks_exact_mut(2) {
+        pair.swap(0, 1);
+    }
 }
 
 #[cfg(test)]
@@ -75,4 +82,11 @@ mod tests {
     fn test_lower_three() {
         assert_eq!(lower_three(TEST_VALUE), 0xbcd);
     }
+
+    #[test]
+    fn test_swap_byte_order() {
+        let mut buffer = vec![0xe0, 0x00, 0x34, 0x12, 0xff];
+        swap_byte_order(&mut buffer);
+        assert_eq!(buffer, vec![0x00, 0xe0, 0x12, 0x34, 0xff]);
+    }
 }
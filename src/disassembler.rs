@@ -0,0 +1,342 @@
+use crate::instruction::{decode, Instruction};
+use crate::labels::Labels;
+
+use std::collections::BTreeSet;
+
+// Translate a single opcode into a human-readable mnemonic, unknown words become data
+pub fn disassemble_opcode(opcode: u16) -> String {
+    use Instruction::*;
+
+    match decode(opcode) {
+        ClearScreen => String::from("CLS"),
+        Return => String::from("RET"),
+        ScrollDown(n) => format!("SCD {}", n),
+        ScrollRight => String::from("SCR"),
+        ScrollLeft => String::from("SCL"),
+        LowResolution => String::from("LOW"),
+        HighResolution => String::from("HIGH"),
+        MachineCode(address) => format!("SYS 0x{:03X}", address),
+        Jump(address) => format!("JP 0x{:03X}", address),
+        Call(address) => format!("CALL 0x{:03X}", address),
+        SkipIfEqual(x, byte) => format!("SE V{:X}, 0x{:02X}", x, byte),
+        SkipIfNotEqual(x, byte) => format!("SNE V{:X}, 0x{:02X}", x, byte),
+        SkipIfRegistersEqual(x, y) => format!("SE V{:X}, V{:X}", x, y),
+        SetRegister(x, byte) => format!("LD V{:X}, 0x{:02X}", x, byte),
+        AddToRegister(x, byte) => format!("ADD V{:X}, 0x{:02X}", x, byte),
+        CopyRegister(x, y) => format!("LD V{:X}, V{:X}", x, y),
+        Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+        And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+        Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+        Add(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+        Subtract(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+        ShiftRight(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+        SubtractFrom(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+        ShiftLeft(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+        SkipIfRegistersNotEqual(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+        SetIndex(address) => format!("LD I, 0x{:03X}", address),
+        JumpWithOffset(address) => format!("JP V0, 0x{:03X}", address),
+        Random(x, byte) => format!("RND V{:X}, 0x{:02X}", x, byte),
+        DrawSprite { x, y, height } => format!("DRW V{:X}, V{:X}, {}", x, y, height),
+        SkipIfKey(x) => format!("SKP V{:X}", x),
+        SkipIfNotKey(x) => format!("SKNP V{:X}", x),
+        LongIndex => String::from("LD I, LONG"),
+        SelectPlanes(planes) => format!("PLANE {}", planes),
+        LoadAudioPattern => String::from("AUDIO"),
+        GetDelayTimer(x) => format!("LD V{:X}, DT", x),
+        WaitForKey(x) => format!("LD V{:X}, K", x),
+        SetDelayTimer(x) => format!("LD DT, V{:X}", x),
+        SetSoundTimer(x) => format!("LD ST, V{:X}", x),
+        AddToIndex(x) => format!("ADD I, V{:X}", x),
+        SetFont(x) => format!("LD F, V{:X}", x),
+        SetLargeFont(x) => format!("LD HF, V{:X}", x),
+        StoreBcd(x) => format!("LD B, V{:X}", x),
+        SetPitch(x) => format!("LD PITCH, V{:X}", x),
+        StoreRegisters(x) => format!("LD [I], V{:X}", x),
+        LoadRegisters(x) => format!("LD V{:X}, [I]", x),
+        StoreFlags(x) => format!("LD R, V{:X}", x),
+        LoadFlags(x) => format!("LD V{:X}, R", x),
+        Unknown(opcode) => data_word(opcode),
+    }
+}
+
+// Like disassemble_opcode, but jump and call targets with a label are shown by name
+pub fn disassemble_opcode_labeled(opcode: u16, labels: &Labels) -> String {
+    let name = |address: u16| labels.get(usize::from(address));
+
+    match decode(opcode) {
+        Instruction::Jump(address) => match name(address) {
+            Some(name) => format!("JP {}", name),
+            None => disassemble_opcode(opcode),
+        },
+        Instruction::Call(address) => match name(address) {
+            Some(name) => format!("CALL {}", name),
+            None => disassemble_opcode(opcode),
+        },
+        Instruction::JumpWithOffset(address) => match name(address) {
+            Some(name) => format!("JP V0, {}", name),
+            None => disassemble_opcode(opcode),
+        },
+        _ => disassemble_opcode(opcode),
+    }
+}
+
+// Disassemble a buffer loaded at the given address into source the assembler accepts, each
+// line is annotated with address and raw word. A trailing odd byte becomes a DB directive.
+pub fn disassemble(buffer: &[u8], start: usize) -> String {
+    let mut source = String::new();
+
+    for (index, chunk) in buffer.chunks(2).enumerate() {
+        let address = start + index * 2;
+
+        let line = match chunk {
+            [upper, lower] => instruction_line(u16::from(*upper) << 8 | u16::from(*lower), address),
+            _ => data_line(chunk[0], address),
+        };
+
+        source.push_str(&line);
+    }
+
+    source
+}
+
+// List every word of a buffer loaded at the given address as `0x200: 6A02  LD VA, 0x02`, for
+// reading rather than reassembling. A trailing odd byte is listed as DB.
+pub fn listing(buffer: &[u8], start: usize, labels: &Labels) -> String {
+    let mut listing = String::new();
+
+    for (index, chunk) in buffer.chunks(2).enumerate() {
+        let address = start + index * 2;
+
+        let line = match chunk {
+            [upper, lower] => {
+                let opcode = u16::from(*upper) << 8 | u16::from(*lower);
+                format!(
+                    "0x{:03X}: {:04X}  {}\n",
+                    address,
+                    opcode,
+                    disassemble_opcode_labeled(opcode, labels)
+                )
+            }
+            _ => format!(
+                "0x{:03X}: {:02X}    DB 0x{:02X}\n",
+                address, chunk[0], chunk[0]
+            ),
+        };
+
+        listing.push_str(&line);
+    }
+
+    listing
+}
+
+// Like disassemble, but only addresses reachable from the start of the buffer are decoded as
+// instructions, everything else becomes DB directives. Targets of BNNN depend on V0 and are
+// not followed, the jump is marked instead. Labeled addresses start with a `name:` line.
+pub fn disassemble_reachable(buffer: &[u8], start: usize, labels: &Labels) -> String {
+    let code = reachable(buffer, start);
+    let mut source = String::new();
+    let mut offset = 0;
+
+    while offset < buffer.len() {
+        let address = start + offset;
+
+        if let Some(name) = labels.get(address) {
+            source.push_str(&format!("{}:\n", name));
+        }
+
+        if code.contains(&address) {
+            let opcode = u16::from(buffer[offset]) << 8 | u16::from(buffer[offset + 1]);
+            let mut line = format!(
+                "{:<16}; 0x{:03X}: {:04X}\n",
+                disassemble_opcode_labeled(opcode, labels),
+                address,
+                opcode
+            );
+
+            if let Instruction::JumpWithOffset(_) = decode(opcode) {
+                line.insert_str(line.len() - 1, "  indirect, targets unknown");
+            }
+
+            source.push_str(&line);
+            offset += 2;
+        } else {
+            source.push_str(&data_line(buffer[offset], address));
+            offset += 1;
+        }
+    }
+
+    source
+}
+
+// Walk the control flow from the start of the buffer and collect the address of every
+// instruction which can be executed
+fn reachable(buffer: &[u8], start: usize) -> BTreeSet<usize> {
+    let mut code = BTreeSet::new();
+    let mut pending = vec![start];
+
+    while let Some(address) = pending.pop() {
+        if address < start || address + 2 > start + buffer.len() || !code.insert(address) {
+            continue;
+        }
+
+        let offset = address - start;
+        let opcode = u16::from(buffer[offset]) << 8 | u16::from(buffer[offset + 1]);
+        let next = address + 2;
+
+        // Skips jump over the address word of F000 NNNN as well
+        let skipped = match buffer.get(next - start..next - start + 2) {
+            Some([0xF0, 0x00]) => next + 4,
+            _ => next + 2,
+        };
+
+        match decode(opcode) {
+            Instruction::Unknown(_) => {
+                // Not an instruction, so execution cannot continue past it
+                code.remove(&address);
+            }
+            Instruction::Return | Instruction::JumpWithOffset(_) => {}
+            Instruction::LongIndex => pending.push(next + 2),
+            Instruction::Jump(target) => pending.push(usize::from(target)),
+            Instruction::Call(target) => pending.extend_from_slice(&[usize::from(target), next]),
+            Instruction::SkipIfEqual(..)
+            | Instruction::SkipIfNotEqual(..)
+            | Instruction::SkipIfRegistersEqual(..)
+            | Instruction::SkipIfRegistersNotEqual(..)
+            | Instruction::SkipIfKey(_)
+            | Instruction::SkipIfNotKey(_) => pending.extend_from_slice(&[next, skipped]),
+            _ => pending.push(next),
+        }
+    }
+
+    code
+}
+
+// Format an instruction with its address and raw word
+fn instruction_line(opcode: u16, address: usize) -> String {
+    format!(
+        "{:<16}; 0x{:03X}: {:04X}\n",
+        disassemble_opcode(opcode),
+        address,
+        opcode
+    )
+}
+
+// Format a single byte of data with its address
+fn data_line(byte: u8, address: usize) -> String {
+    format!(
+        "{:<16}; 0x{:03X}: {:02X}\n",
+        format!("DB 0x{:02X}", byte),
+        address,
+        byte
+    )
+}
+
+// Format a word which does not decode to a known instruction
+fn data_word(opcode: u16) -> String {
+    format!("DW 0x{:04X}", opcode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble_flow_control() {
+        assert_eq!(disassemble_opcode(0x00E0), "CLS");
+        assert_eq!(disassemble_opcode(0x00EE), "RET");
+        assert_eq!(disassemble_opcode(0x00C4), "SCD 4");
+        assert_eq!(disassemble_opcode(0x1234), "JP 0x234");
+        assert_eq!(disassemble_opcode(0x2ABC), "CALL 0xABC");
+        assert_eq!(disassemble_opcode(0xB300), "JP V0, 0x300");
+    }
+
+    #[test]
+    fn test_disassemble_registers() {
+        assert_eq!(disassemble_opcode(0x6A02), "LD VA, 0x02");
+        assert_eq!(disassemble_opcode(0x8124), "ADD V1, V2");
+        assert_eq!(disassemble_opcode(0xD125), "DRW V1, V2, 5");
+        assert_eq!(disassemble_opcode(0xF365), "LD V3, [I]");
+    }
+
+    #[test]
+    fn test_disassemble_unknown() {
+        assert_eq!(disassemble_opcode(0x5121), "DW 0x5121");
+        assert_eq!(disassemble_opcode(0x812F), "DW 0x812F");
+        assert_eq!(disassemble_opcode(0xE1FF), "DW 0xE1FF");
+        assert_eq!(disassemble_opcode(0xF100), "DW 0xF100");
+        assert_eq!(disassemble_opcode(0xF200), "DW 0xF200");
+    }
+
+    #[test]
+    fn test_disassemble_reachable() {
+        // Call a subroutine skipping over a sprite, then loop forever
+        let rom = vec![
+            0x22, 0x06, 0x12, 0x02, 0xF0, 0x90, 0x30, 0x01, 0xB3, 0x00, 0x00, 0xEE,
+        ];
+        let source = disassemble_reachable(&rom, 0x200, &Labels::default());
+        let lines: Vec<&str> = source.lines().collect();
+
+        assert_eq!(lines[0], "CALL 0x206      ; 0x200: 2206");
+        assert_eq!(lines[1], "JP 0x202        ; 0x202: 1202");
+        assert_eq!(lines[2], "DB 0xF0         ; 0x204: F0");
+        assert_eq!(lines[3], "DB 0x90         ; 0x205: 90");
+        assert_eq!(lines[4], "SE V0, 0x01     ; 0x206: 3001");
+        assert_eq!(
+            lines[5],
+            "JP V0, 0x300    ; 0x208: B300  indirect, targets unknown"
+        );
+        assert_eq!(lines[6], "RET             ; 0x20A: 00EE");
+    }
+
+    #[test]
+    fn test_disassemble_reachable_long_index() {
+        // Skip over a four byte F000 NNNN, its address word is data
+        let rom = vec![0x30, 0x01, 0xF0, 0x00, 0x12, 0x34, 0x12, 0x06];
+        let source = disassemble_reachable(&rom, 0x200, &Labels::default());
+        let lines: Vec<&str> = source.lines().collect();
+
+        assert_eq!(lines[1], "LD I, LONG      ; 0x202: F000");
+        assert_eq!(lines[2], "DB 0x12         ; 0x204: 12");
+        assert_eq!(lines[3], "DB 0x34         ; 0x205: 34");
+        assert_eq!(lines[4], "JP 0x206        ; 0x206: 1206");
+    }
+
+    #[test]
+    fn test_listing() {
+        let rom = vec![0x6A, 0x02, 0x12, 0x00, 0xF1, 0x00, 0xAB];
+        let mut labels = Labels::default();
+        labels.insert(0x200, "main");
+
+        assert_eq!(
+            listing(&rom, 0x200, &labels),
+            "0x200: 6A02  LD VA, 0x02\n\
+             0x202: 1200  JP main\n\
+             0x204: F100  DW 0xF100\n\
+             0x206: AB    DB 0xAB\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_labeled() {
+        let mut labels = Labels::default();
+        labels.insert(0x240, "draw_player");
+
+        assert_eq!(
+            disassemble_opcode_labeled(0x2240, &labels),
+            "CALL draw_player"
+        );
+        assert_eq!(
+            disassemble_opcode_labeled(0x1240, &labels),
+            "JP draw_player"
+        );
+        assert_eq!(disassemble_opcode_labeled(0x1242, &labels), "JP 0x242");
+        assert_eq!(disassemble_opcode_labeled(0xA240, &labels), "LD I, 0x240");
+
+        let source = disassemble_reachable(&[0x12, 0x00], 0x200, &{
+            let mut labels = Labels::default();
+            labels.insert(0x200, "main");
+            labels
+        });
+        assert_eq!(source, "main:\nJP main         ; 0x200: 1200\n");
+    }
+}
@@ -0,0 +1,220 @@
+use crate::bin::*;
+
+// A decoded opcode, shared by the interpreter and the disassembler. Registers are given by
+// their number, X and Y in the opcode patterns below.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    // 00E0
+    ClearScreen,
+    // 00EE
+    Return,
+    // 00CN, SUPER-CHIP
+    ScrollDown(u8),
+    // 00FE, SUPER-CHIP, 64x32 pixels
+    LowResolution,
+    // 00FF, SUPER-CHIP, 128x64 pixels
+    HighResolution,
+    // 00FB, SUPER-CHIP, by 4 pixels
+    ScrollRight,
+    // 00FC, SUPER-CHIP, by 4 pixels
+    ScrollLeft,
+    // 0NNN, any other 0 opcode calls machine code, 0FNN is a test assertion in test mode
+    MachineCode(u16),
+    // 1NNN
+    Jump(u16),
+    // 2NNN
+    Call(u16),
+    // 3XNN
+    SkipIfEqual(u8, u8),
+    // 4XNN
+    SkipIfNotEqual(u8, u8),
+    // 5XY0
+    SkipIfRegistersEqual(u8, u8),
+    // 6XNN
+    SetRegister(u8, u8),
+    // 7XNN
+    AddToRegister(u8, u8),
+    // 8XY0
+    CopyRegister(u8, u8),
+    // 8XY1
+    Or(u8, u8),
+    // 8XY2
+    And(u8, u8),
+    // 8XY3
+    Xor(u8, u8),
+    // 8XY4
+    Add(u8, u8),
+    // 8XY5
+    Subtract(u8, u8),
+    // 8XY6
+    ShiftRight(u8, u8),
+    // 8XY7
+    SubtractFrom(u8, u8),
+    // 8XYE
+    ShiftLeft(u8, u8),
+    // 9XY0
+    SkipIfRegistersNotEqual(u8, u8),
+    // ANNN
+    SetIndex(u16),
+    // BNNN
+    JumpWithOffset(u16),
+    // CXNN
+    Random(u8, u8),
+    // DXYN
+    DrawSprite { x: u8, y: u8, height: u8 },
+    // EX9E
+    SkipIfKey(u8),
+    // EXA1
+    SkipIfNotKey(u8),
+    // F000 NNNN, XO-CHIP, four bytes long with the 16 bit address in the second word
+    LongIndex,
+    // FN01, XO-CHIP, N selects the planes as bitmask from 0 to 3
+    SelectPlanes(u8),
+    // F002, XO-CHIP, 16 bytes from I
+    LoadAudioPattern,
+    // FX07
+    GetDelayTimer(u8),
+    // FX0A
+    WaitForKey(u8),
+    // FX15
+    SetDelayTimer(u8),
+    // FX18
+    SetSoundTimer(u8),
+    // FX1E
+    AddToIndex(u8),
+    // FX29
+    SetFont(u8),
+    // FX30, SUPER-CHIP, 10 rows tall digit
+    SetLargeFont(u8),
+    // FX33
+    StoreBcd(u8),
+    // FX3A, XO-CHIP
+    SetPitch(u8),
+    // FX55
+    StoreRegisters(u8),
+    // FX65
+    LoadRegisters(u8),
+    // FX75, SUPER-CHIP, X up to 7
+    StoreFlags(u8),
+    // FX85, SUPER-CHIP, X up to 7
+    LoadFlags(u8),
+    // Anything else
+    Unknown(u16),
+}
+
+// Decode an opcode into the instruction it stands for
+pub fn decode(opcode: u16) -> Instruction {
+    use Instruction::*;
+
+    let x = to_byte(second_nibble(opcode));
+    let y = to_byte(third_nibble(opcode));
+    let n = to_byte(fourth_nibble(opcode));
+    let byte = to_byte(lower_half(opcode));
+    let address = lower_three(opcode);
+
+    match first_nibble(opcode) {
+        0x0 => match opcode {
+            0x00E0 => ClearScreen,
+            0x00EE => Return,
+            0x00FB => ScrollRight,
+            0x00FC => ScrollLeft,
+            0x00FE => LowResolution,
+            0x00FF => HighResolution,
+            _ if opcode & 0xFFF0 == 0x00C0 => ScrollDown(n),
+            _ => MachineCode(address),
+        },
+        0x1 => Jump(address),
+        0x2 => Call(address),
+        0x3 => SkipIfEqual(x, byte),
+        0x4 => SkipIfNotEqual(x, byte),
+        0x5 if n == 0x0 => SkipIfRegistersEqual(x, y),
+        0x6 => SetRegister(x, byte),
+        0x7 => AddToRegister(x, byte),
+        0x8 => match n {
+            0x0 => CopyRegister(x, y),
+            0x1 => Or(x, y),
+            0x2 => And(x, y),
+            0x3 => Xor(x, y),
+            0x4 => Add(x, y),
+            0x5 => Subtract(x, y),
+            0x6 => ShiftRight(x, y),
+            0x7 => SubtractFrom(x, y),
+            0xE => ShiftLeft(x, y),
+            _ => Unknown(opcode),
+        },
+        0x9 if n == 0x0 => SkipIfRegistersNotEqual(x, y),
+        0xA => SetIndex(address),
+        0xB => JumpWithOffset(address),
+        0xC => Random(x, byte),
+        0xD => DrawSprite { x, y, height: n },
+        0xE => match byte {
+            0x9E => SkipIfKey(x),
+            0xA1 => SkipIfNotKey(x),
+            _ => Unknown(opcode),
+        },
+        // Decoded by second nibble and lower half together: XO-CHIP adds F000 NNNN, FN01 and
+        // F002 which only exist for specific values of the second nibble, so an opcode like
+        // F100 must never alias another instruction of the family
+        0xF => match (x, byte) {
+            (0, 0x00) => LongIndex,
+            (planes, 0x01) if planes <= 3 => SelectPlanes(planes),
+            (0, 0x02) => LoadAudioPattern,
+            (_, 0x07) => GetDelayTimer(x),
+            (_, 0x0A) => WaitForKey(x),
+            (_, 0x15) => SetDelayTimer(x),
+            (_, 0x18) => SetSoundTimer(x),
+            (_, 0x1E) => AddToIndex(x),
+            (_, 0x29) => SetFont(x),
+            (_, 0x30) => SetLargeFont(x),
+            (_, 0x33) => StoreBcd(x),
+            (_, 0x3A) => SetPitch(x),
+            (_, 0x55) => StoreRegisters(x),
+            (_, 0x65) => LoadRegisters(x),
+            (_, 0x75) => StoreFlags(x),
+            (_, 0x85) => LoadFlags(x),
+            _ => Unknown(opcode),
+        },
+        _ => Unknown(opcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Instruction::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(0x00E0), ClearScreen);
+        assert_eq!(decode(0x00C4), ScrollDown(4));
+        assert_eq!(decode(0x00FB), ScrollRight);
+        assert_eq!(decode(0x00FF), HighResolution);
+        assert_eq!(decode(0x0123), MachineCode(0x123));
+        assert_eq!(decode(0x1234), Jump(0x234));
+        assert_eq!(decode(0x6A02), SetRegister(0xA, 0x02));
+        assert_eq!(decode(0x812E), ShiftLeft(1, 2));
+        assert_eq!(
+            decode(0xD125),
+            DrawSprite {
+                x: 1,
+                y: 2,
+                height: 5
+            }
+        );
+        assert_eq!(decode(0xF000), LongIndex);
+        assert_eq!(decode(0xF301), SelectPlanes(3));
+        assert_eq!(decode(0xF002), LoadAudioPattern);
+        assert_eq!(decode(0xF230), SetLargeFont(2));
+        assert_eq!(decode(0xF365), LoadRegisters(3));
+    }
+
+    #[test]
+    fn test_decode_unknown() {
+        assert_eq!(decode(0x5121), Unknown(0x5121));
+        assert_eq!(decode(0x812F), Unknown(0x812F));
+        assert_eq!(decode(0xE1FF), Unknown(0xE1FF));
+        assert_eq!(decode(0xF100), Unknown(0xF100));
+        assert_eq!(decode(0xF401), Unknown(0xF401));
+        assert_eq!(decode(0xF102), Unknown(0xF102));
+    }
+}
@@ -0,0 +1,103 @@
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+// Names for addresses, shown instead of jump and call targets in listings and traces
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Labels {
+    names: BTreeMap<usize, String>,
+}
+
+impl Labels {
+    // Parse one `address name` pair per line, blank lines and lines starting with '#' are
+    // skipped. Errors name the line they occurred on (starting at 1).
+    pub fn parse(text: &str) -> Result<Labels, String> {
+        let mut labels = Labels::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let (address, name) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(address), Some(name), None) => (address, name),
+                _ => return Err(format!("line {}: expected `address name`", index + 1)),
+            };
+
+            let address = match address
+                .strip_prefix("0x")
+                .or_else(|| address.strip_prefix("0X"))
+            {
+                Some(hex) => usize::from_str_radix(hex, 16),
+                None => address.parse(),
+            }
+            .map_err(|_| format!("line {}: invalid address {}", index + 1, address))?;
+
+            labels.insert(address, name);
+        }
+
+        Ok(labels)
+    }
+
+    // Name an address, replacing any previous name
+    pub fn insert(&mut self, address: usize, name: &str) {
+        self.names.insert(address, String::from(name));
+    }
+
+    // Get the name of an address
+    pub fn get(&self, address: usize) -> Option<&str> {
+        self.names.get(&address).map(String::as_str)
+    }
+}
+
+// Read the labels shipped next to a ROM as `<rom>.sym` if present, malformed files are ignored
+// with a warning
+pub fn load_sidecar(rom_path: &str) -> Option<Labels> {
+    let sidecar_path = format!("{}.sym", rom_path);
+
+    if !Path::new(&sidecar_path).is_file() {
+        return None;
+    }
+
+    let parsed = fs::read_to_string(&sidecar_path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| Labels::parse(&text));
+
+    match parsed {
+        Ok(labels) => {
+            info!("Using labels from {}", sidecar_path);
+            Some(labels)
+        }
+        Err(e) => {
+            warn!("Ignoring malformed labels {}: {}", sidecar_path, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_labels() {
+        let labels = Labels::parse("# Sprites\n0x240 draw_player\n\n600 main_loop\n").unwrap();
+        assert_eq!(labels.get(0x240), Some("draw_player"));
+        assert_eq!(labels.get(600), Some("main_loop"));
+        assert_eq!(labels.get(0x200), None);
+    }
+
+    #[test]
+    fn test_parse_labels_errors() {
+        assert_eq!(
+            Labels::parse("0x200 start\n0x2G0 broken").unwrap_err(),
+            "line 2: invalid address 0x2G0"
+        );
+        assert!(Labels::parse("0x200").is_err());
+        assert!(Labels::parse("0x200 two names").is_err());
+    }
+}
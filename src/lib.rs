@@ -0,0 +1,20 @@
+// chirpy as a library, to embed the interpreter into other programs like test harnesses or
+// other frontends. The chirpy binary is a command line frontend on top of it.
+pub mod assembler;
+pub mod audio;
+pub mod batch;
+pub mod bin;
+pub mod disassembler;
+pub mod instruction;
+pub mod labels;
+pub mod library;
+pub mod metadata;
+pub mod periphery;
+pub mod system;
+
+pub use bin::{
+    first_nibble, fourth_nibble, lower_half, lower_three, second_nibble, swap_byte_order,
+    third_nibble, to_byte, to_usize,
+};
+pub use periphery::Periphery;
+pub use system::System;
@@ -0,0 +1,124 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// File extensions of ROMs picked up from the library directory
+const ROM_EXTENSIONS: &[&str] = &["ch8", "c8", "sc8", "xo8"];
+
+// Outcome of looking up a ROM by name
+#[derive(Debug, PartialEq)]
+pub enum Lookup {
+    Found(PathBuf),
+    Ambiguous(Vec<PathBuf>),
+    NotFound,
+}
+
+// Get the ROM library directory, CHIRPY_ROMS if set, otherwise ~/.chirpy/roms
+pub fn directory() -> Option<PathBuf> {
+    if let Some(directory) = env::var_os("CHIRPY_ROMS") {
+        return Some(PathBuf::from(directory));
+    }
+
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(|home| Path::new(&home).join(".chirpy").join("roms"))
+}
+
+// Find a ROM in the directory and its subdirectories by file name without extension, ignoring
+// case. An exact match wins, otherwise the name has to be part of exactly one file name.
+pub fn find(name: &str, directory: &Path) -> Lookup {
+    let name = name.to_lowercase();
+    let mut roms = vec![];
+    collect_roms(directory, &mut roms);
+    roms.sort();
+
+    let stem = |path: &PathBuf| {
+        path.file_stem()
+            .map(|stem| stem.to_string_lossy().to_lowercase())
+            .unwrap_or_default()
+    };
+
+    let exact: Vec<PathBuf> = roms
+        .iter()
+        .filter(|rom| stem(rom) == name)
+        .cloned()
+        .collect();
+    let mut matches: Vec<PathBuf> = if exact.is_empty() {
+        roms.into_iter()
+            .filter(|rom| stem(rom).contains(&name))
+            .collect()
+    } else {
+        exact
+    };
+
+    match matches.len() {
+        0 => Lookup::NotFound,
+        1 => Lookup::Found(matches.remove(0)),
+        _ => Lookup::Ambiguous(matches),
+    }
+}
+
+// Collect ROM files below a directory, unreadable directories are skipped
+fn collect_roms(directory: &Path, roms: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for path in entries.filter_map(Result::ok).map(|entry| entry.path()) {
+        if path.is_dir() {
+            collect_roms(&path, roms);
+            continue;
+        }
+
+        let is_rom = match path.extension() {
+            Some(extension) => ROM_EXTENSIONS
+                .iter()
+                .any(|known| extension.eq_ignore_ascii_case(known)),
+            None => false,
+        };
+
+        if is_rom {
+            roms.push(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let directory = env::temp_dir().join("chirpy-test-library");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(directory.join("games")).unwrap();
+        for file in &[
+            "games/Tetris.ch8",
+            "games/Tetris Deluxe.ch8",
+            "Pong.c8",
+            "pong.txt",
+        ] {
+            fs::write(directory.join(file), [0x12, 0x00]).unwrap();
+        }
+
+        assert_eq!(
+            find("tetris", &directory),
+            Lookup::Found(directory.join("games/Tetris.ch8"))
+        );
+        assert_eq!(
+            find("po", &directory),
+            Lookup::Found(directory.join("Pong.c8"))
+        );
+        assert_eq!(
+            find("t", &directory),
+            Lookup::Ambiguous(vec![
+                directory.join("games/Tetris Deluxe.ch8"),
+                directory.join("games/Tetris.ch8"),
+            ])
+        );
+        assert_eq!(find("breakout", &directory), Lookup::NotFound);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,25 +1,670 @@
-mod bin;
-mod periphery;
-mod system;
+use chirpy::{
+    assembler, audio, batch, bin, disassembler, labels, library, metadata, periphery, system,
+};
 
+use audio::WavRecorder;
+use library::Lookup;
+use periphery::{DisplayOptions, KeyLayout, Periphery, SocdFilter, SocdMode};
+use system::{
+    MachineCodePolicy, Platform, StackOverflowPolicy, System, UnknownOpcodePolicy,
+    ZeroHeightDrawPolicy,
+};
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::io::BufReader;
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Settings parsed from the command line
+struct Options {
+    rom_path: Option<String>,
+    load_address: usize,
+    protect_fontset: bool,
+    trace_unique: bool,
+    debug_clears: bool,
+    check_executable: bool,
+    check_alignment: bool,
+    timer_stats: bool,
+    frame_stats: bool,
+    quiet: bool,
+    memory_map: bool,
+    test_mode: bool,
+    byte_swap: bool,
+    disasm: bool,
+    disasm_reachable: bool,
+    call_graph_path: Option<String>,
+    record_audio_path: Option<String>,
+    register_log_path: Option<String>,
+    memory_fill: Option<String>,
+    machine_code_policy: MachineCodePolicy,
+    stack_size: Option<usize>,
+    stack_overflow_policy: StackOverflowPolicy,
+    unknown_opcode_policy: UnknownOpcodePolicy,
+    strict: bool,
+    zero_height_draw_policy: ZeroHeightDrawPolicy,
+    key_layout: KeyLayout,
+    socd_mode: Option<SocdMode>,
+    socd_pairs: Vec<(u8, u8)>,
+    display_options: DisplayOptions,
+    colors: Option<(u32, u32)>,
+    input_delay: usize,
+    boot_delay: usize,
+    start_paused: bool,
+    vip_timing: bool,
+    quirk_shift: bool,
+    quirk_memory: bool,
+    quirk_jump: bool,
+    quirk_vblank: bool,
+    platform: Option<Platform>,
+    max_draws_per_frame: Option<u32>,
+    min_beep_ticks: u8,
+    shift_amount: u8,
+    patches: HashMap<usize, u16>,
+    batch_directory: Option<String>,
+    cycles: u64,
+    clock: Option<u32>,
+    fps: Option<u32>,
+    frame_skip: usize,
+}
+
+impl Options {
+    // Parse options from the process arguments
+    fn parse(args: &[String]) -> Options {
+        let mut options = Options {
+            rom_path: None,
+            load_address: 0x200,
+            protect_fontset: false,
+            trace_unique: false,
+            debug_clears: false,
+            check_executable: false,
+            check_alignment: false,
+            timer_stats: false,
+            frame_stats: false,
+            quiet: false,
+            memory_map: false,
+            test_mode: false,
+            byte_swap: false,
+            disasm: false,
+            disasm_reachable: false,
+            call_graph_path: None,
+            record_audio_path: None,
+            register_log_path: None,
+            memory_fill: None,
+            machine_code_policy: MachineCodePolicy::default(),
+            stack_size: None,
+            stack_overflow_policy: StackOverflowPolicy::default(),
+            unknown_opcode_policy: UnknownOpcodePolicy::default(),
+            strict: false,
+            zero_height_draw_policy: ZeroHeightDrawPolicy::default(),
+            key_layout: KeyLayout::default(),
+            socd_mode: None,
+            socd_pairs: vec![],
+            display_options: DisplayOptions::default(),
+            colors: None,
+            input_delay: 0,
+            boot_delay: 0,
+            start_paused: false,
+            vip_timing: false,
+            quirk_shift: false,
+            quirk_memory: false,
+            quirk_jump: false,
+            quirk_vblank: false,
+            platform: None,
+            max_draws_per_frame: None,
+            min_beep_ticks: 0,
+            shift_amount: 1,
+            patches: HashMap::new(),
+            batch_directory: None,
+            cycles: 100_000,
+            clock: None,
+            fps: None,
+            frame_skip: 0,
+        };
+
+        // Environment variables come first so arguments override them
+        let mut args = environment_args().into_iter().chain(args.iter().cloned());
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--protect-fontset" => options.protect_fontset = true,
+                "--trace-unique" => options.trace_unique = true,
+                "--debug-clears" => options.debug_clears = true,
+                "--start-paused" => options.start_paused = true,
+                "--vip-timing" => options.vip_timing = true,
+                "--quirk-shift" => options.quirk_shift = true,
+                "--quirk-memory" => options.quirk_memory = true,
+                "--quirk-jump" => options.quirk_jump = true,
+                "--quirk-vblank" => options.quirk_vblank = true,
+                "--check-executable" => options.check_executable = true,
+                "--check-alignment" => options.check_alignment = true,
+                "--timer-stats" => options.timer_stats = true,
+                "--frame-stats" => options.frame_stats = true,
+                "--quiet" => options.quiet = true,
+                "--memory-map" => options.memory_map = true,
+                "--test-mode" => options.test_mode = true,
+                "--byte-swap" => options.byte_swap = true,
+                "--disasm" => options.disasm = true,
+                "--disasm-reachable" => options.disasm_reachable = true,
+                "--load-address" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--load-address requires an address."));
+                    options.load_address = parse_number(&value);
+                }
+                "--memory-fill" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--memory-fill requires a byte or 'random'."));
+                    options.memory_fill = Some(value);
+                }
+                "--machine-code" => {
+                    let value = args.next().unwrap_or_default();
+                    options.machine_code_policy = match value.as_str() {
+                        "strict" => MachineCodePolicy::Strict,
+                        "ignore" => MachineCodePolicy::Ignore,
+                        "halt" => MachineCodePolicy::Halt,
+                        _ => panic!("--machine-code must be one of strict, ignore or halt."),
+                    };
+                }
+                "--stack-size" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--stack-size requires a number of levels."));
+                    options.stack_size = Some(parse_number(&value));
+                }
+                "--stack-overflow" => {
+                    let value = args.next().unwrap_or_default();
+                    options.stack_overflow_policy = match value.as_str() {
+                        "error" => StackOverflowPolicy::Error,
+                        "wrap" => StackOverflowPolicy::Wrap,
+                        "ignore" => StackOverflowPolicy::Ignore,
+                        _ => panic!("--stack-overflow must be one of error, wrap or ignore."),
+                    };
+                }
+                "--unknown-opcode" => {
+                    let value = args.next().unwrap_or_default();
+                    options.unknown_opcode_policy = match value.as_str() {
+                        "halt" => UnknownOpcodePolicy::Halt,
+                        "skip" => UnknownOpcodePolicy::Skip,
+                        _ => panic!("--unknown-opcode must be one of halt or skip."),
+                    };
+                }
+                "--strict" => options.strict = true,
+                "--zero-height-draw" => {
+                    let value = args.next().unwrap_or_default();
+                    options.zero_height_draw_policy = match value.as_str() {
+                        "empty" => ZeroHeightDrawPolicy::Empty,
+                        "error" => ZeroHeightDrawPolicy::Error,
+                        "large" => ZeroHeightDrawPolicy::Large,
+                        _ => panic!("--zero-height-draw must be one of empty, error or large."),
+                    };
+                }
+                "--keyboard-layout" => {
+                    let value = args.next().unwrap_or_default();
+                    options.key_layout = match value.as_str() {
+                        "qwerty" => KeyLayout::Qwerty,
+                        "qwertz" => KeyLayout::Qwertz,
+                        "azerty" => KeyLayout::Azerty,
+                        "dvorak" => KeyLayout::Dvorak,
+                        "colemak" => KeyLayout::Colemak,
+                        _ => panic!(
+                            "--keyboard-layout must be one of qwerty, qwertz, azerty, dvorak or colemak."
+                        ),
+                    };
+                }
+                "--socd" => {
+                    let value = args.next().unwrap_or_default();
+                    options.socd_mode = match value.as_str() {
+                        "neutral" => Some(SocdMode::Neutral),
+                        "last" => Some(SocdMode::LastPressed),
+                        _ => panic!("--socd must be one of neutral or last."),
+                    };
+                }
+                "--socd-pair" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--socd-pair requires KEY,KEY."));
+                    let keys: Vec<u8> = value
+                        .splitn(2, ',')
+                        .map(|key| match u8::from_str_radix(key, 16) {
+                            Ok(key_code) if key_code <= 0xf => key_code,
+                            _ => panic!("Invalid CHIP-8 key in --socd-pair: {}", value),
+                        })
+                        .collect();
+                    if keys.len() != 2 {
+                        panic!("--socd-pair requires KEY,KEY: {}", value);
+                    }
+                    options.socd_pairs.push((keys[0], keys[1]));
+                }
+                "--window-size" => {
+                    let value = args.next().unwrap_or_default();
+                    match DisplayOptions::parse_window_size(&value) {
+                        Ok(size) => options.display_options.window_size = Some(size),
+                        Err(e) => {
+                            eprintln!("--window-size: {}", e);
+                            process::exit(2);
+                        }
+                    }
+                }
+                "--scale" => {
+                    let value = args.next().unwrap_or_default();
+                    match DisplayOptions::parse_scale(&value) {
+                        Ok(scale) => options.display_options.scale = Some(scale),
+                        Err(e) => {
+                            eprintln!("--scale: {}", e);
+                            process::exit(2);
+                        }
+                    }
+                }
+                "--colors" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--colors requires FOREGROUND,BACKGROUND."));
+                    let colors: Result<Vec<u32>, String> =
+                        value.split(',').map(periphery::parse_color).collect();
+                    match colors.as_deref() {
+                        Ok([fg, bg]) => options.colors = Some((*fg, *bg)),
+                        Ok(_) => panic!("--colors requires FOREGROUND,BACKGROUND: {}", value),
+                        Err(e) => {
+                            eprintln!("--colors: {}", e);
+                            process::exit(2);
+                        }
+                    }
+                }
+                "--window-position" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--window-position requires X,Y."));
+                    let mut parts = value.splitn(2, ',');
+                    let x = parse_signed_number(parts.next().unwrap_or_default());
+                    let y = parse_signed_number(parts.next().unwrap_or_default());
+                    options.display_options.window_position = Some((x, y));
+                }
+                "--persist" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--persist requires a number of frames."));
+                    let frames = parse_number(&value);
+                    if frames > 0xff {
+                        panic!("--persist supports at most 255 frames: {}", value);
+                    }
+                    options.display_options.persistence = frames as u8;
+                }
+                "--max-draws-per-frame" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--max-draws-per-frame requires a number."));
+                    let limit = u32::try_from(parse_number(&value)).unwrap_or_else(|_| {
+                        panic!("--max-draws-per-frame is too large: {}", value)
+                    });
+                    if limit == 0 {
+                        panic!("--max-draws-per-frame must be at least 1: {}", value);
+                    }
+                    options.max_draws_per_frame = Some(limit);
+                }
+                "--shift-amount" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--shift-amount requires a number of bits."));
+                    let amount = parse_number(&value);
+                    if !(1..=7).contains(&amount) {
+                        panic!("--shift-amount must be between 1 and 7: {}", value);
+                    }
+                    options.shift_amount = amount as u8;
+                }
+                "--min-beep-ticks" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--min-beep-ticks requires a number of frames."));
+                    let ticks = parse_number(&value);
+                    if ticks > 0xff {
+                        panic!("--min-beep-ticks supports at most 255 frames: {}", value);
+                    }
+                    options.min_beep_ticks = ticks as u8;
+                }
+                "--input-delay" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--input-delay requires a number of frames."));
+                    options.input_delay = parse_number(&value);
+                }
+                "--boot-delay" => {
+                    let value = args.next().unwrap_or_else(|| {
+                        panic!("--boot-delay requires a number of milliseconds.")
+                    });
+                    options.boot_delay = parse_number(&value);
+                }
+                "--patch" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--patch requires ADDRESS=OPCODE."));
+                    let mut parts = value.splitn(2, '=');
+                    let address = parse_number(parts.next().unwrap_or_default());
+                    let opcode = parse_number(parts.next().unwrap_or_default());
+                    if opcode > 0xffff {
+                        panic!("Patched opcode must be 16 bits: {}", value);
+                    }
+                    options.patches.insert(address, opcode as u16);
+                }
+                "--batch" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--batch requires a directory."));
+                    options.batch_directory = Some(value);
+                }
+                "--call-graph" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--call-graph requires an output path."));
+                    options.call_graph_path = Some(value);
+                }
+                "--record-audio" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--record-audio requires an output path."));
+                    options.record_audio_path = Some(value);
+                }
+                "--register-log" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--register-log requires an output path."));
+                    options.register_log_path = Some(value);
+                }
+                "--fps" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--fps requires a number of frames per second."));
+                    let fps = parse_number(&value);
+                    if fps > 1_000 {
+                        panic!("--fps supports at most 1000 frames per second: {}", value);
+                    }
+                    options.fps = Some(fps as u32);
+                }
+                "--frame-skip" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--frame-skip requires a number of frames."));
+                    options.frame_skip = parse_number(&value);
+                }
+                "--cycles" => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("--cycles requires a number."));
+                    options.cycles = parse_number(&value) as u64;
+                }
+                "--platform" => {
+                    let value = args.next().unwrap_or_default();
+                    match Platform::parse(&value) {
+                        Ok(platform) => options.platform = Some(platform),
+                        Err(e) => {
+                            eprintln!("--platform: {}", e);
+                            process::exit(2);
+                        }
+                    }
+                }
+                "--clock" => {
+                    let value = args.next().unwrap_or_default();
+                    match System::parse_clock_speed(&value) {
+                        Ok(hz) => options.clock = Some(hz),
+                        Err(e) => {
+                            eprintln!("--clock: {}", e);
+                            process::exit(2);
+                        }
+                    }
+                }
+                _ if options.rom_path.is_none() => options.rom_path = Some(arg),
+                _ => panic!("Unexpected argument: {}", arg),
+            }
+        }
+
+        options
+    }
+
+    // Apply the platform and then the quirks given as option on top of it
+    fn apply_quirks(&self, system: &mut System) {
+        if let Some(platform) = self.platform {
+            system.set_platform(platform);
+        }
+
+        if self.quirk_shift {
+            system.set_quirk_shift_uses_vy(true);
+        }
+
+        if self.quirk_memory {
+            system.set_quirk_memory_increments_index(true);
+        }
+
+        if self.quirk_jump {
+            system.set_quirk_jump_uses_vx(true);
+        }
+
+        if self.quirk_vblank || self.vip_timing {
+            system.set_quirk_display_wait(true);
+        }
+    }
+
+    // Apply settings which do not depend on how the system was constructed
+    fn configure(&self, system: &mut System) {
+        system.set_fontset_write_protection(self.protect_fontset);
+        system.set_debug_clears(self.debug_clears);
+
+        // Distinct opcodes go to stderr, stdout may be piped to other output
+        if self.trace_unique {
+            system.set_trace_unique(Box::new(io::stderr()));
+        }
+
+        system.set_machine_code_policy(self.machine_code_policy);
+        system.set_stack_overflow_policy(self.stack_overflow_policy);
+        system.set_unknown_opcode_policy(self.unknown_opcode_policy);
+        system.set_strict(self.strict);
+        system.set_zero_height_draw_policy(self.zero_height_draw_policy);
+        system.set_key_layout(self.key_layout);
+
+        // Without explicit pairs the filter resolves the usual direction keys
+        if let Some(mode) = self.socd_mode {
+            let pairs = if self.socd_pairs.is_empty() {
+                SocdFilter::DIRECTION_PAIRS.to_vec()
+            } else {
+                self.socd_pairs.clone()
+            };
+            system.set_socd_filter(SocdFilter::new(mode, pairs));
+        }
+
+        system.set_input_delay(self.input_delay);
+        system.set_boot_delay(Duration::from_millis(self.boot_delay as u64));
+        system.set_start_paused(self.start_paused);
+        system.set_max_draws_per_frame(self.max_draws_per_frame);
+        system.set_min_beep_ticks(self.min_beep_ticks);
+        system.set_shift_amount(self.shift_amount);
+        system.set_alignment_check(self.check_alignment);
+        system.set_call_graph_recording(self.call_graph_path.is_some());
+
+        if let Some(clock) = self.clock {
+            system.set_clock_speed(clock);
+        }
+
+        system.set_frame_skip(self.frame_skip as u32);
+        system.set_vip_timing(self.vip_timing);
+        self.apply_quirks(system);
+
+        if let Some((fg, bg)) = self.colors {
+            system.set_colors(fg, bg);
+        }
+
+        if let Some(fps) = self.fps {
+            system.set_frame_rate(fps);
+        }
+
+        if let Some(stack_size) = self.stack_size {
+            system.set_stack_size(stack_size);
+        }
+
+        if self.check_executable {
+            system.set_executable_range(Some(System::program_range()));
+        }
+
+        // Execute patched opcodes instead of the ones in memory, leaving the ROM untouched
+        if !self.patches.is_empty() {
+            let patches = self.patches.clone();
+            system.set_on_fetch(move |address, opcode| match patches.get(&address) {
+                Some(patched) => *patched,
+                None => opcode,
+            });
+        }
+    }
+}
 
 fn main() {
-    // Initialize new system
-    let mut system = system::System::default();
+    // Log warnings and errors unless configured otherwise via RUST_LOG
+    env_logger::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    // Handle tool subcommands which do not run a ROM
+    let args: Vec<String> = env::args().skip(1).collect();
+    let play = match args.first().map(String::as_str) {
+        Some("asm") => process::exit(run_assembler(&args[1..])),
+        Some("disasm") => process::exit(run_disassembler(&args[1..])),
+        Some("play") => true,
+        _ => false,
+    };
 
     // Parse arguments
-    let mut args = env::args_os();
+    let mut options = Options::parse(if play { &args[1..] } else { &args });
 
-    if args.len() != 2 {
-        panic!("Please supply the path to a valid ROM as first argument.")
+    // Look the ROM up by name in the library, names without a match are used as a path
+    if play {
+        if let (Some(name), Some(directory)) = (&options.rom_path, library::directory()) {
+            match library::find(name, &directory) {
+                Lookup::Found(path) => options.rom_path = Some(path.to_string_lossy().into_owned()),
+                Lookup::Ambiguous(paths) => {
+                    eprintln!("{} matches several ROMs:", name);
+                    for path in paths {
+                        eprintln!("  {}", path.display());
+                    }
+                    process::exit(1);
+                }
+                Lookup::NotFound => {}
+            }
+        }
+    }
+
+    // Only report errors, leaving stdout and stderr to requested output
+    if options.quiet {
+        log::set_max_level(log::LevelFilter::Error);
+    }
+
+    // Run a whole directory of ROMs headless instead of a single one
+    if let Some(directory) = &options.batch_directory {
+        let clean = batch::run_batch(
+            Path::new(directory),
+            options.cycles,
+            options.load_address,
+            |system| options.configure(system),
+        );
+        process::exit(if clean { 0 } else { 1 });
+    }
+
+    // Only list the ROM, either every word or the code reachable from the load address
+    if options.disasm || options.disasm_reachable {
+        let buffer = read_rom(&options);
+        let labels = options
+            .rom_path
+            .as_ref()
+            .and_then(|path| labels::load_sidecar(path))
+            .unwrap_or_default();
+
+        if options.disasm_reachable {
+            print!(
+                "{}",
+                disassembler::disassemble_reachable(&buffer, options.load_address, &labels)
+            );
+        } else {
+            print!(
+                "{}",
+                disassembler::listing(&buffer, options.load_address, &labels)
+            );
+        }
+        process::exit(0);
+    }
+
+    // Run a self-testing ROM headless and report the result via the exit code
+    if options.test_mode {
+        process::exit(run_test_rom(&options));
+    }
+
+    // Initialize new system
+    let mut periphery = Periphery::with_options(options.display_options);
+
+    if let Some(path) = &options.record_audio_path {
+        let recorder = WavRecorder::create(Path::new(path)).unwrap_or_else(|e| panic!("{}", e));
+        periphery.add_audio(Box::new(recorder));
+    }
+
+    let mut system = System::with_periphery(periphery);
+
+    if let Some(value) = &options.memory_fill {
+        if value == "random" {
+            system.fill_memory_randomly(rand::random());
+        } else if let Some(seed) = value.strip_prefix("random:") {
+            let seed = seed
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid memory fill seed: {}", seed));
+            system.fill_memory_randomly(seed);
+        } else {
+            let fill = parse_number(value);
+            if fill > 0xff {
+                panic!("Memory fill must be a single byte: {}", value);
+            }
+            system.fill_memory(fill as u8);
+        }
+    }
+
+    options.configure(&mut system);
+
+    if let Some(path) = &options.register_log_path {
+        let file = File::create(path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+        system.set_register_log(Box::new(BufWriter::new(file)));
     }
 
     // Load ROM from disk and put it into memory
-    let path = args.nth(1).unwrap();
+    let path = options
+        .rom_path
+        .clone()
+        .unwrap_or_else(|| panic!("Please supply the path to a valid ROM as first argument."));
+
+    // Apply settings shipped alongside the ROM
+    if let Some(metadata) = metadata::load_sidecar(&path) {
+        metadata.apply(&mut system);
+    }
+
+    // A clock given as option wins over the tickrate of the ROM
+    if let Some(clock) = options.clock {
+        system.set_clock_speed(clock);
+    }
+
+    // The platform, quirks and colors given as option win over the ROM metadata as well
+    if let Some((fg, bg)) = options.colors {
+        system.set_colors(fg, bg);
+    }
+
+    options.apply_quirks(&mut system);
+
+    if let Some(labels) = labels::load_sidecar(&path) {
+        system.set_labels(labels);
+    }
+
+    // SUPER-CHIP flag registers are kept next to the ROM
+    system.set_flags_path(Some(PathBuf::from(format!("{}.flags", path))));
+
+    // F5 saves the state next to the ROM and F9 restores it
+    system.set_state_path(Some(PathBuf::from(format!("{}.state", path))));
+
     let file = File::open(path).unwrap_or_else(|e| {
         panic!("{}", e);
     });
@@ -27,8 +672,235 @@ fn main() {
     let mut reader = BufReader::new(file);
     let mut buffer: Vec<u8> = vec![];
     reader.read_to_end(&mut buffer).unwrap();
-    system.copy_buffer_to_memory(buffer, 0x200);
+
+    // Some assemblers emit little-endian opcodes
+    if options.byte_swap {
+        bin::swap_byte_order(&mut buffer);
+    }
+
+    if let Err(e) = system.copy_buffer_to_memory(buffer, options.load_address) {
+        eprintln!("{}", e);
+        drop(system);
+        process::exit(1);
+    }
+
+    // The first Ctrl-C pauses emulation and asks how to go on, a second one while paused quits.
+    // run returns then, so the audio recording is finished like after closing the window.
+    let interrupts = Arc::new(AtomicUsize::new(0));
+    let handler_interrupts = Arc::clone(&interrupts);
+    if let Err(e) = ctrlc::set_handler(move || {
+        handler_interrupts.fetch_add(1, Ordering::SeqCst);
+    }) {
+        log::warn!("Unable to install Ctrl-C handler: {}", e);
+    }
+    system.set_interrupt_counter(interrupts);
 
     // Run system
     system.run();
+
+    if options.timer_stats {
+        println!("{}", system.timer_stats());
+    }
+
+    if options.frame_stats {
+        println!("{}", system.frame_stats());
+    }
+
+    if options.memory_map {
+        println!("{}", system.memory_map());
+    }
+
+    if let (Some(path), Some(call_graph)) = (&options.call_graph_path, system.call_graph()) {
+        fs::write(path, call_graph.to_dot()).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    // The error was logged when it halted the system, the window stayed open until closed.
+    // Exiting skips destructors, so the system goes first to finish the audio recording.
+    let failed = system.error().is_some();
+    drop(system);
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+// Options which can also be set through environment variables, the value of a flag without
+// a value has to be 1 or true
+const ENVIRONMENT_OPTIONS: &[(&str, &str, bool)] = &[
+    ("CHIRPY_LOAD_ADDRESS", "--load-address", true),
+    ("CHIRPY_MEMORY_FILL", "--memory-fill", true),
+    ("CHIRPY_MACHINE_CODE", "--machine-code", true),
+    ("CHIRPY_STACK_SIZE", "--stack-size", true),
+    ("CHIRPY_STACK_OVERFLOW", "--stack-overflow", true),
+    ("CHIRPY_UNKNOWN_OPCODE", "--unknown-opcode", true),
+    ("CHIRPY_ZERO_HEIGHT_DRAW", "--zero-height-draw", true),
+    ("CHIRPY_KEYBOARD_LAYOUT", "--keyboard-layout", true),
+    ("CHIRPY_SOCD", "--socd", true),
+    ("CHIRPY_WINDOW_SIZE", "--window-size", true),
+    ("CHIRPY_WINDOW_POSITION", "--window-position", true),
+    ("CHIRPY_SCALE", "--scale", true),
+    ("CHIRPY_COLORS", "--colors", true),
+    ("CHIRPY_PERSIST", "--persist", true),
+    ("CHIRPY_INPUT_DELAY", "--input-delay", true),
+    ("CHIRPY_BOOT_DELAY", "--boot-delay", true),
+    ("CHIRPY_MAX_DRAWS_PER_FRAME", "--max-draws-per-frame", true),
+    ("CHIRPY_MIN_BEEP_TICKS", "--min-beep-ticks", true),
+    ("CHIRPY_SHIFT_AMOUNT", "--shift-amount", true),
+    ("CHIRPY_CYCLES", "--cycles", true),
+    ("CHIRPY_CLOCK_HZ", "--clock", true),
+    ("CHIRPY_PLATFORM", "--platform", true),
+    ("CHIRPY_FPS", "--fps", true),
+    ("CHIRPY_FRAME_SKIP", "--frame-skip", true),
+    ("CHIRPY_PROTECT_FONTSET", "--protect-fontset", false),
+    ("CHIRPY_CHECK_EXECUTABLE", "--check-executable", false),
+    ("CHIRPY_CHECK_ALIGNMENT", "--check-alignment", false),
+    ("CHIRPY_QUIET", "--quiet", false),
+    ("CHIRPY_STRICT", "--strict", false),
+    ("CHIRPY_START_PAUSED", "--start-paused", false),
+    ("CHIRPY_VIP_TIMING", "--vip-timing", false),
+    ("CHIRPY_QUIRK_SHIFT", "--quirk-shift", false),
+    ("CHIRPY_QUIRK_MEMORY", "--quirk-memory", false),
+    ("CHIRPY_QUIRK_JUMP", "--quirk-jump", false),
+    ("CHIRPY_QUIRK_VBLANK", "--quirk-vblank", false),
+];
+
+// Translate environment variables into arguments, for scripts and containers where passing
+// arguments is awkward
+fn environment_args() -> Vec<String> {
+    let mut args = vec![];
+
+    for (variable, flag, takes_value) in ENVIRONMENT_OPTIONS {
+        let value = match env::var(variable) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+
+        if *takes_value {
+            args.push(String::from(*flag));
+            args.push(value);
+        } else if value == "1" || value.eq_ignore_ascii_case("true") {
+            args.push(String::from(*flag));
+        }
+    }
+
+    args
+}
+
+// Read the ROM given on the command line, swapping byte order if requested
+fn read_rom(options: &Options) -> Vec<u8> {
+    let path = options
+        .rom_path
+        .as_ref()
+        .unwrap_or_else(|| panic!("Please supply the path to a valid ROM as first argument."));
+    let mut buffer = fs::read(path).unwrap_or_else(|e| panic!("{}", e));
+
+    if options.byte_swap {
+        bin::swap_byte_order(&mut buffer);
+    }
+
+    buffer
+}
+
+// Run a ROM in test mode for the cycle budget, returns 0 if it passed, 1 if an assertion
+// failed or an instruction could not be executed and 2 if it never signalled success
+fn run_test_rom(options: &Options) -> i32 {
+    let buffer = read_rom(options);
+
+    let mut system = System::headless();
+    options.configure(&mut system);
+    system.set_test_mode(true);
+    if let Err(e) = system.copy_buffer_to_memory(buffer, options.load_address) {
+        eprintln!("{}", e);
+        return 1;
+    }
+    system.run_cycles(options.cycles);
+
+    let results = system.test_results();
+
+    for (address, code) in &results.failures {
+        println!("Assertion {} failed at address {:#X}", code, address);
+    }
+
+    if let Some(e) = system.error() {
+        println!("{}", e);
+    }
+
+    if !results.failures.is_empty() || system.error().is_some() {
+        println!("FAILED");
+        1
+    } else if results.passed {
+        println!("PASSED");
+        0
+    } else {
+        println!("UNFINISHED");
+        2
+    }
+}
+
+// Split tool arguments into the input path and the optional -o output path
+fn parse_tool_args(args: &[String]) -> (String, Option<String>) {
+    let mut input = None;
+    let mut output = None;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().expect("-o requires a path.").clone()),
+            _ if input.is_none() => input = Some(arg.clone()),
+            _ => panic!("Unexpected argument: {}", arg),
+        }
+    }
+
+    (input.expect("Please supply an input file."), output)
+}
+
+// Assemble a source file into a ROM, returns the process exit code
+fn run_assembler(args: &[String]) -> i32 {
+    let (input, output) = parse_tool_args(args);
+    let output = output.expect("asm requires an output path via -o.");
+    let source = fs::read_to_string(&input).unwrap_or_else(|e| panic!("{}", e));
+
+    match assembler::assemble(&source) {
+        Ok(rom) => {
+            fs::write(&output, rom).unwrap_or_else(|e| panic!("{}", e));
+            0
+        }
+        Err(e) => {
+            eprintln!("{}: {}", input, e);
+            1
+        }
+    }
+}
+
+// Disassemble a ROM into source, written to stdout without an output path
+fn run_disassembler(args: &[String]) -> i32 {
+    let (input, output) = parse_tool_args(args);
+    let rom = fs::read(&input).unwrap_or_else(|e| panic!("{}", e));
+    let source = disassembler::disassemble(&rom, 0x200);
+
+    match output {
+        Some(output) => fs::write(&output, source).unwrap_or_else(|e| panic!("{}", e)),
+        None => print!("{}", source),
+    }
+
+    0
+}
+
+// Parse a decimal or 0x-prefixed hexadecimal number
+fn parse_number(value: &str) -> usize {
+    let parsed = if value.starts_with("0x") || value.starts_with("0X") {
+        usize::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse()
+    };
+
+    parsed.unwrap_or_else(|_| panic!("Invalid number: {}", value))
+}
+
+// Parse a decimal number which may be negative, e.g. a window coordinate on a monitor left of
+// or above the primary one
+fn parse_signed_number(value: &str) -> isize {
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("Invalid number: {}", value))
 }
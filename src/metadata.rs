@@ -0,0 +1,142 @@
+use crate::periphery::{self, BACKGROUND_COLOR, DRAW_COLOR};
+use crate::system::{Platform, System};
+
+use log::{info, warn};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+// Settings shipped next to a ROM as `<rom>.json`, using the option names of Octo cartridges
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RomMetadata {
+    pub platform: Option<String>,
+    pub tickrate: Option<u32>,
+    pub fill_color: Option<String>,
+    pub background_color: Option<String>,
+    pub shift_quirks: Option<bool>,
+    pub load_store_quirks: Option<bool>,
+    pub jump_quirks: Option<bool>,
+}
+
+impl RomMetadata {
+    // Parse metadata from JSON, unknown fields are ignored
+    pub fn from_json(json: &str) -> Result<RomMetadata, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    // Apply all settings chirpy supports, everything else keeps its default
+    pub fn apply(&self, system: &mut System) {
+        // The platform comes first, so the quirks below can change single quirks of it
+        if let Some(platform) = &self.platform {
+            match Platform::parse(platform) {
+                Ok(platform) => system.set_platform(platform),
+                Err(e) => warn!("{} in ROM metadata, using the default quirks", e),
+            }
+        }
+
+        if let Some(tickrate) = self.tickrate {
+            system.set_cycles_per_frame(tickrate);
+        }
+
+        // A color which is missing or cannot be parsed keeps its default
+        if self.fill_color.is_some() || self.background_color.is_some() {
+            let color = |value: &Option<String>, default: u32| match value {
+                Some(value) => periphery::parse_color(value).unwrap_or_else(|e| {
+                    warn!("{} in ROM metadata, using the default", e);
+                    default
+                }),
+                None => default,
+            };
+
+            system.set_colors(
+                color(&self.fill_color, DRAW_COLOR),
+                color(&self.background_color, BACKGROUND_COLOR),
+            );
+        }
+
+        // Octo's shift quirk is shifting VX in place, which is the default here
+        if let Some(shift_quirks) = self.shift_quirks {
+            system.set_quirk_shift_uses_vy(!shift_quirks);
+        }
+
+        // Octo's load/store quirk is leaving I unchanged, which is the default here
+        if let Some(load_store_quirks) = self.load_store_quirks {
+            system.set_quirk_memory_increments_index(!load_store_quirks);
+        }
+
+        if let Some(jump_quirks) = self.jump_quirks {
+            system.set_quirk_jump_uses_vx(jump_quirks);
+        }
+    }
+}
+
+// Read the sidecar metadata of a ROM if present, malformed files are ignored with a warning
+pub fn load_sidecar(rom_path: &str) -> Option<RomMetadata> {
+    let sidecar_path = format!("{}.json", rom_path);
+
+    if !Path::new(&sidecar_path).is_file() {
+        return None;
+    }
+
+    let json = match fs::read_to_string(&sidecar_path) {
+        Ok(json) => json,
+        Err(e) => {
+            warn!("Unable to read {}: {}", sidecar_path, e);
+            return None;
+        }
+    };
+
+    match RomMetadata::from_json(&json) {
+        Ok(metadata) => {
+            info!("Using ROM metadata from {}", sidecar_path);
+            Some(metadata)
+        }
+        Err(e) => {
+            warn!("Ignoring malformed ROM metadata {}: {}", sidecar_path, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_metadata() {
+        let metadata = RomMetadata::from_json(
+            r##"{"platform": "chip8", "tickrate": 15, "fillColor": "#FFCC00", "author": "someone"}"##,
+        )
+        .unwrap();
+
+        assert_eq!(metadata.platform.as_deref(), Some("chip8"));
+        assert_eq!(metadata.tickrate, Some(15));
+        assert_eq!(metadata.fill_color.as_deref(), Some("#FFCC00"));
+        assert_eq!(metadata.background_color, None);
+    }
+
+    #[test]
+    fn test_parse_empty_metadata() {
+        assert_eq!(
+            RomMetadata::from_json("{}").unwrap(),
+            RomMetadata::default()
+        );
+    }
+
+    #[test]
+    fn test_apply_tickrate() {
+        let mut system = System::headless();
+        let metadata = RomMetadata {
+            tickrate: Some(30),
+            ..RomMetadata::default()
+        };
+
+        metadata.apply(&mut system);
+        system
+            .copy_buffer_to_memory(vec![0x12, 0x00], 0x200)
+            .unwrap();
+        system.run_cycles(61);
+        assert_eq!(system.frame_count(), 2);
+    }
+}
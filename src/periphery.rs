@@ -1,125 +1,1175 @@
-use minifb::{Key, Window, WindowOptions};
-use rodio::{source::SineWave, Sink};
+use crate::audio::{Audio, RodioAudio, BEEP_FREQ, PATTERN_RATE};
+
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+use std::fmt;
+use std::thread::sleep;
+use std::time::Duration;
 
 // Screen dimensions
 pub const SCREEN_WIDTH: u16 = 64;
 pub const SCREEN_HEIGHT: u16 = 32;
 pub const SCREEN_SIZE: usize = 64 * 32;
 
-// Screen scale
-const WINDOW_SCALE: minifb::Scale = minifb::Scale::X16;
+// Screen dimensions in the SUPER-CHIP high resolution mode
+pub const HIRES_WIDTH: u16 = 128;
+pub const HIRES_HEIGHT: u16 = 64;
+pub const HIRES_SIZE: usize = 128 * 64;
+
+// XO-CHIP display planes, pixel bit n is set while the pixel is lit on plane n
+pub const PLANE_COUNT: u8 = 2;
+
+// Screen scale used unless another is chosen
+const DEFAULT_WINDOW_SCALE: u8 = 16;
+
+// Scale factors minifb supports
+const WINDOW_SCALES: [(u8, minifb::Scale); 6] = [
+    (1, minifb::Scale::X1),
+    (2, minifb::Scale::X2),
+    (4, minifb::Scale::X4),
+    (8, minifb::Scale::X8),
+    (16, minifb::Scale::X16),
+    (32, minifb::Scale::X32),
+];
+
+// Default background color
+pub const BACKGROUND_COLOR: u32 = 0x00_00_00;
+
+// Default draw color on screen (RGB)
+pub const DRAW_COLOR: u32 = 0xff_ff_ff;
+
+// Colors of pixels lit only on the second XO-CHIP plane and on both planes
+const PLANE_1_COLOR: u32 = 0xff_66_00;
+const BOTH_PLANES_COLOR: u32 = 0xff_cc_00;
+
+// Color and duration of the flash shown before the screen is cleared with --debug-clears
+const FLASH_COLOR: u32 = 0xff_00_00;
+const FLASH_DURATION: Duration = Duration::from_millis(150);
+
+// How often the window is refreshed while waiting for a key
+const KEY_POLL_INTERVAL: Duration = Duration::from_millis(16);
+
+// Key which fast-forwards emulation while held
+const TURBO_KEY: Key = Key::Tab;
 
-// Background color
-const BACKGROUND_COLOR: u32 = 0x00_00_00;
+// Keys which save and restore the machine state
+const SAVE_STATE_KEY: Key = Key::F5;
+const LOAD_STATE_KEY: Key = Key::F9;
 
-// Draw color on screen (RGB)
-const DRAW_COLOR: u32 = 0xff_ff_ff;
+// Host keyboard layout used to place the hex keypad. minifb reports keys by the character
+// they produce, so each layout lists the keys which sit on the physical 1-4/Q-R/A-F/Z-V block
+// of a US keyboard. This keeps the keypad in the same spot, but the labels printed on the keys
+// no longer match the CHIP-8 key names on non-QWERTY layouts.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum KeyLayout {
+    #[default]
+    Qwerty,
+    Qwertz,
+    Azerty,
+    Dvorak,
+    Colemak,
+}
+
+impl KeyLayout {
+    // Get the host key for every CHIP-8 key, indexed by key code
+    #[rustfmt::skip]
+    fn key_map(self) -> [Key; 16] {
+        match self {
+            KeyLayout::Qwerty => [
+                Key::X, Key::Key1, Key::Key2, Key::Key3, // 0 1 2 3
+                Key::Q, Key::W, Key::E, Key::A, // 4 5 6 7
+                Key::S, Key::D, Key::Z, Key::C, // 8 9 A B
+                Key::Key4, Key::R, Key::F, Key::V, // C D E F
+            ],
+            KeyLayout::Qwertz => [
+                Key::X, Key::Key1, Key::Key2, Key::Key3, // 0 1 2 3
+                Key::Q, Key::W, Key::E, Key::A, // 4 5 6 7
+                Key::S, Key::D, Key::Y, Key::C, // 8 9 A B
+                Key::Key4, Key::R, Key::F, Key::V, // C D E F
+            ],
+            KeyLayout::Azerty => [
+                Key::X, Key::Key1, Key::Key2, Key::Key3, // 0 1 2 3
+                Key::A, Key::Z, Key::E, Key::Q, // 4 5 6 7
+                Key::S, Key::D, Key::W, Key::C, // 8 9 A B
+                Key::Key4, Key::R, Key::F, Key::V, // C D E F
+            ],
+            KeyLayout::Dvorak => [
+                Key::Q, Key::Key1, Key::Key2, Key::Key3, // 0 1 2 3
+                Key::Apostrophe, Key::Comma, Key::Period, Key::A, // 4 5 6 7
+                Key::O, Key::E, Key::Semicolon, Key::J, // 8 9 A B
+                Key::Key4, Key::P, Key::U, Key::K, // C D E F
+            ],
+            KeyLayout::Colemak => [
+                Key::X, Key::Key1, Key::Key2, Key::Key3, // 0 1 2 3
+                Key::Q, Key::W, Key::F, Key::A, // 4 5 6 7
+                Key::R, Key::S, Key::Z, Key::C, // 8 9 A B
+                Key::Key4, Key::P, Key::T, Key::V, // C D E F
+            ],
+        }
+    }
+}
 
-// Sine beep frequency in Hz
-const BEEP_FREQ: u32 = 440;
+// A host key is bound to two different CHIP-8 keys or to a key code which does not exist
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyMapError {
+    Conflict { key: Key, key_codes: (u8, u8) },
+    InvalidKeyCode { key: Key, key_code: u8 },
+}
+
+impl fmt::Display for KeyMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyMapError::Conflict { key, key_codes } => write!(
+                f,
+                "{:?} is bound to both CHIP-8 key {:X} and {:X}",
+                key, key_codes.0, key_codes.1
+            ),
+            KeyMapError::InvalidKeyCode { key, key_code } => write!(
+                f,
+                "{:?} is bound to CHIP-8 key {:#X} which does not exist",
+                key, key_code
+            ),
+        }
+    }
+}
+
+// Bindings of host keys to CHIP-8 key codes. Several host keys may be bound to the same
+// CHIP-8 key on purpose, e.g. to have both WASD and the arrow keys steer. A host key bound to
+// more than one CHIP-8 key is a mistake which validate reports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyMap {
+    bindings: Vec<(Key, u8)>,
+}
+
+impl KeyMap {
+    // Bind the keypad block of a keyboard layout
+    pub fn from_layout(layout: KeyLayout) -> KeyMap {
+        let bindings = layout
+            .key_map()
+            .iter()
+            .enumerate()
+            .map(|(key_code, key)| (*key, key_code as u8))
+            .collect();
+
+        KeyMap { bindings }
+    }
+
+    // Bind another host key, keys already bound to the CHIP-8 key stay bound as aliases
+    pub fn bind(&mut self, key: Key, key_code: u8) {
+        self.bindings.push((key, key_code));
+    }
+
+    // Check every host key is bound to a single existing CHIP-8 key
+    pub fn validate(&self) -> Result<(), KeyMapError> {
+        for (index, (key, key_code)) in self.bindings.iter().enumerate() {
+            if *key_code > 0xf {
+                return Err(KeyMapError::InvalidKeyCode {
+                    key: *key,
+                    key_code: *key_code,
+                });
+            }
+
+            let conflict = self.bindings[..index]
+                .iter()
+                .find(|(other_key, other_code)| other_key == key && other_code != key_code);
+
+            if let Some((_, other_code)) = conflict {
+                return Err(KeyMapError::Conflict {
+                    key: *key,
+                    key_codes: (*other_code, *key_code),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    // Translate a host key into a CHIP-8 key code, otherwise 0xff
+    fn key_code(&self, key: Key) -> u8 {
+        match self.bindings.iter().find(|(bound, _)| *bound == key) {
+            Some((_, key_code)) => *key_code,
+            None => 0xff,
+        }
+    }
+}
+
+// How a pair of opposite keys held at the same time is resolved, like SOCD cleaning on game
+// controllers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SocdMode {
+    // Neither key counts as held
+    Neutral,
+    // Only the key pressed later counts as held
+    LastPressed,
+}
+
+// Filter for pairs of CHIP-8 keys which cancel each other out, e.g. left and right. Without
+// pairs every key is passed through.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SocdFilter {
+    mode: SocdMode,
+    pairs: Vec<(u8, u8)>,
+    // Keys held in the previous sample, to tell which key of a pair was pressed later
+    previous: Vec<u8>,
+    // Key of each pair which was pressed last
+    last_pressed: Vec<Option<u8>>,
+}
+
+impl Default for SocdFilter {
+    fn default() -> SocdFilter {
+        SocdFilter::new(SocdMode::Neutral, vec![])
+    }
+}
+
+impl SocdFilter {
+    // Directions on the common CHIP-8 keypad layout: 2 and 8 for up and down, 4 and 6 for left
+    // and right
+    pub const DIRECTION_PAIRS: [(u8, u8); 2] = [(0x2, 0x8), (0x4, 0x6)];
+
+    // Resolve the given pairs of CHIP-8 keys with a mode
+    pub fn new(mode: SocdMode, pairs: Vec<(u8, u8)>) -> SocdFilter {
+        let last_pressed = vec![None; pairs.len()];
+
+        SocdFilter {
+            mode,
+            pairs,
+            previous: vec![],
+            last_pressed,
+        }
+    }
+
+    // Drop keys of held pairs from a sample of held CHIP-8 keys according to the mode
+    pub fn apply(&mut self, held: &mut Vec<u8>) {
+        let sample = held.clone();
+
+        for (index, &(first, second)) in self.pairs.iter().enumerate() {
+            for key in &[first, second] {
+                if sample.contains(key) && !self.previous.contains(key) {
+                    self.last_pressed[index] = Some(*key);
+                }
+            }
+
+            if !(sample.contains(&first) && sample.contains(&second)) {
+                continue;
+            }
+
+            match (self.mode, self.last_pressed[index]) {
+                (SocdMode::LastPressed, Some(winner)) => {
+                    held.retain(|key| *key == winner || (*key != first && *key != second))
+                }
+                _ => held.retain(|key| *key != first && *key != second),
+            }
+        }
+
+        self.previous = sample;
+    }
+}
+
+// Settings for the window created by the periphery
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DisplayOptions {
+    // Fixed window size in pixels, the display is letterboxed into it
+    pub window_size: Option<(usize, usize)>,
+
+    // Keep pixels lit which were on in any of the last N drawn frames, disabled for N <= 1
+    pub persistence: u8,
+
+    // Position of the top left window corner on the desktop, chosen by the OS if None.
+    // minifb can neither query the monitor size nor request focus, so centering is up to
+    // the window manager.
+    pub window_position: Option<(isize, isize)>,
+
+    // Factor the display is scaled by when the window size is not fixed, 16 if None
+    pub scale: Option<u8>,
+}
+
+impl DisplayOptions {
+    // Parse a window scale factor, which has to be one minifb supports
+    pub fn parse_scale(value: &str) -> Result<u8, String> {
+        let valid = || {
+            WINDOW_SCALES
+                .iter()
+                .map(|(factor, _)| factor.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+
+        match value.parse::<u8>() {
+            Ok(factor) if WINDOW_SCALES.iter().any(|(known, _)| *known == factor) => Ok(factor),
+            _ => Err(format!(
+                "Invalid window scale {}, valid scales are {}",
+                value,
+                valid()
+            )),
+        }
+    }
+
+    // Parse a window size given as WIDTHxHEIGHT, which has to fit the high resolution display
+    pub fn parse_window_size(value: &str) -> Result<(usize, usize), String> {
+        let mut parts = value.splitn(2, 'x').map(str::parse::<usize>);
+
+        match (parts.next(), parts.next()) {
+            (Some(Ok(width)), Some(Ok(height)))
+                if width >= usize::from(HIRES_WIDTH) && height >= usize::from(HIRES_HEIGHT) =>
+            {
+                Ok((width, height))
+            }
+            (Some(Ok(_)), Some(Ok(_))) => Err(format!(
+                "Window size {} is smaller than the {}x{} display",
+                value, HIRES_WIDTH, HIRES_HEIGHT
+            )),
+            _ => Err(format!(
+                "Invalid window size {}, expected WIDTHxHEIGHT",
+                value
+            )),
+        }
+    }
+}
 
 pub struct Periphery {
-    pub framebuffer: [u8; SCREEN_SIZE],
-    window: Window,
-    audio_sink: Sink,
+    // One byte per pixel of the active resolution, row by row, with one bit per plane
+    pub framebuffer: Vec<u8>,
+    hires: bool,
+    // Planes drawn to, cleared and scrolled, bit n selects plane n
+    selected_planes: u8,
+    window: Option<Window>,
+    // Every sound output, e.g. the speaker and a recording
+    audio: Vec<Box<dyn Audio>>,
+    beep_frequency: u32,
+    // XO-CHIP audio pattern replacing the beep once loaded
+    audio_pattern: Option<[u8; 16]>,
+    key_map: KeyMap,
+    socd_filter: SocdFilter,
+    display_enabled: bool,
+    draw_color: u32,
+    background_color: u32,
+
+    // Frames each pixel stays lit for after it was last on, counting down once per draw
+    persistence: u8,
+    persistence_counters: Vec<u8>,
+
+    // Window size in pixels when letterboxing, otherwise the display is scaled
+    window_size: Option<(usize, usize)>,
 }
 
 impl Default for Periphery {
     // Create a new empty screen
     fn default() -> Periphery {
+        Periphery::with_options(DisplayOptions::default())
+    }
+}
+
+impl Periphery {
+    // Create a new empty screen with the given window settings
+    pub fn with_options(display_options: DisplayOptions) -> Periphery {
+        let window_size = display_options.window_size;
+        // The window always fits the high resolution, low resolution frames are doubled
+        let (width, height, scale) = match window_size {
+            Some((width, height)) => (width, height, minifb::Scale::X1),
+            None => (
+                usize::from(HIRES_WIDTH),
+                usize::from(HIRES_HEIGHT),
+                window_scale(display_options.scale.unwrap_or(DEFAULT_WINDOW_SCALE)),
+            ),
+        };
+
         let options = WindowOptions {
             borderless: false,
             resize: false,
-            scale: WINDOW_SCALE,
+            scale,
             title: true,
         };
 
-        let window = Window::new(
-            "chirpy",
-            usize::from(SCREEN_WIDTH),
-            usize::from(SCREEN_HEIGHT),
-            options,
-        )
-        .unwrap_or_else(|e| {
+        let mut window = Window::new("chirpy", width, height, options).unwrap_or_else(|e| {
             panic!("{}", e);
         });
 
-        let audio_device = rodio::default_output_device().unwrap_or_else(|| {
-            panic!("Unable to initialize default audio device!");
-        });
+        if let Some((x, y)) = display_options.window_position {
+            window.set_position(x, y);
+        }
 
-        let audio_sink = Sink::new(&audio_device);
-        audio_sink.pause();
-        audio_sink.append(SineWave::new(BEEP_FREQ));
+        // Run without sound if no audio device is available
+        let audio = RodioAudio::new()
+            .map(|audio| Box::new(audio) as Box<dyn Audio>)
+            .into_iter()
+            .collect();
 
         Periphery {
-            framebuffer: [0; SCREEN_SIZE],
-            window,
-            audio_sink,
+            framebuffer: vec![0; SCREEN_SIZE],
+            hires: false,
+            selected_planes: 1,
+            window: Some(window),
+            audio,
+            beep_frequency: BEEP_FREQ,
+            audio_pattern: None,
+            key_map: KeyMap::from_layout(KeyLayout::default()),
+            socd_filter: SocdFilter::default(),
+            display_enabled: true,
+            draw_color: DRAW_COLOR,
+            background_color: BACKGROUND_COLOR,
+            persistence: display_options.persistence,
+            persistence_counters: vec![0; SCREEN_SIZE],
+            window_size,
+        }
+    }
+
+    // Create a periphery without window and audio device, input always reads as no key
+    pub fn headless() -> Periphery {
+        Periphery {
+            framebuffer: vec![0; SCREEN_SIZE],
+            hires: false,
+            selected_planes: 1,
+            window: None,
+            audio: vec![],
+            beep_frequency: BEEP_FREQ,
+            audio_pattern: None,
+            key_map: KeyMap::from_layout(KeyLayout::default()),
+            socd_filter: SocdFilter::default(),
+            display_enabled: true,
+            draw_color: DRAW_COLOR,
+            background_color: BACKGROUND_COLOR,
+            persistence: 0,
+            persistence_counters: vec![0; SCREEN_SIZE],
+            window_size: None,
+        }
+    }
+
+    // Get the active display width and height in pixels
+    pub fn resolution(&self) -> (u16, u16) {
+        if self.hires {
+            (HIRES_WIDTH, HIRES_HEIGHT)
+        } else {
+            (SCREEN_WIDTH, SCREEN_HEIGHT)
+        }
+    }
+
+    // Switch between the 64x32 and the 128x64 SUPER-CHIP resolution. The framebuffer is
+    // resized to the new resolution and cleared when the resolution changes.
+    pub fn set_hires(&mut self, enabled: bool) {
+        if self.hires == enabled {
+            return;
+        }
+
+        self.hires = enabled;
+        let (width, height) = self.resolution();
+        let size = usize::from(width) * usize::from(height);
+        self.framebuffer = vec![0; size];
+        self.persistence_counters = vec![0; size];
+    }
+
+    // Check whether the 128x64 resolution is active
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    // Turn off every pixel
+    pub fn clear_framebuffer(&mut self) {
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel = 0;
+        }
+    }
+
+    // Select the XO-CHIP planes following draws, clears and scrolls apply to, bit n selects
+    // plane n. Only plane 0 is selected initially like in every other CHIP-8 variant.
+    pub fn set_selected_planes(&mut self, planes: u8) {
+        self.selected_planes = planes & ((1 << PLANE_COUNT) - 1);
+    }
+
+    // Get the selected planes, bit n is set if plane n is selected
+    pub fn selected_planes(&self) -> u8 {
+        self.selected_planes
+    }
+
+    // Turn off every pixel of the selected planes
+    pub fn clear_selected_planes(&mut self) {
+        let planes = self.selected_planes;
+
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel &= !planes;
+        }
+    }
+
+    // Check whether the window was closed by the user, never true without a window
+    pub fn is_window_closed(&self) -> bool {
+        match &self.window {
+            Some(window) => !window.is_open(),
+            None => false,
         }
     }
-}
 
-impl Periphery {
     // Draw contents of framebuffer to display
     pub fn draw_screen(&mut self) {
-        if self.window.is_open() {
-            let mut buffer_32bits: [u32; SCREEN_SIZE] = [BACKGROUND_COLOR; SCREEN_SIZE];
+        self.update_persistence();
+        let buffer_32bits = self.render(&self.palette());
+        self.present(buffer_32bits);
+    }
+
+    // Replace the whole framebuffer, e.g. to test rendering without running a program. The
+    // pixels have to cover the active resolution exactly, otherwise the framebuffer is left
+    // untouched.
+    pub fn set_framebuffer(&mut self, pixels: &[u8]) -> Result<(), String> {
+        if pixels.len() != self.framebuffer.len() {
+            return Err(format!(
+                "Framebuffer has {} pixels, expected {}",
+                pixels.len(),
+                self.framebuffer.len()
+            ));
+        }
+
+        self.framebuffer.copy_from_slice(pixels);
+        Ok(())
+    }
+
+    // Move the screen content of the selected planes down by a number of pixel rows, rows
+    // moved past the bottom are lost and the rows exposed at the top are cleared
+    pub fn scroll_down(&mut self, rows: usize) {
+        self.scroll(0, rows.min(usize::from(HIRES_HEIGHT)) as isize);
+    }
+
+    // Move the screen content of the selected planes right by a number of columns, columns
+    // moved past the right edge are lost and the columns exposed on the left are cleared
+    pub fn scroll_right(&mut self, columns: usize) {
+        self.scroll(columns.min(usize::from(HIRES_WIDTH)) as isize, 0);
+    }
+
+    // Move the screen content of the selected planes left by a number of columns, columns
+    // moved past the left edge are lost and the columns exposed on the right are cleared
+    pub fn scroll_left(&mut self, columns: usize) {
+        self.scroll(-(columns.min(usize::from(HIRES_WIDTH)) as isize), 0);
+    }
+
+    // Move the selected planes by a number of columns and rows, other planes stay in place
+    fn scroll(&mut self, right: isize, down: isize) {
+        let (width, height) = self.resolution();
+        let (width, height) = (width as isize, height as isize);
+        let planes = self.selected_planes;
+        let source = self.framebuffer.clone();
+
+        for y in 0..height {
+            for x in 0..width {
+                let (from_x, from_y) = (x - right, y - down);
+                let moved = if (0..width).contains(&from_x) && (0..height).contains(&from_y) {
+                    source[(from_y * width + from_x) as usize]
+                } else {
+                    0
+                };
+
+                let pixel = &mut self.framebuffer[(y * width + x) as usize];
+                *pixel = (*pixel & !planes) | (moved & planes);
+            }
+        }
+    }
+
+    // Show the framebuffer in a highlight color for a moment, e.g. right before it is cleared.
+    // Does nothing without a window.
+    pub fn flash_screen(&mut self) {
+        if self.window.is_none() {
+            return;
+        }
+
+        let palette = [self.background_color, FLASH_COLOR, FLASH_COLOR, FLASH_COLOR];
+        let buffer_32bits = self.render(&palette);
+        self.present(buffer_32bits);
+        sleep(FLASH_DURATION);
+    }
+
+    // Keep showing the framebuffer until any key was pressed and released again, so the key
+    // does not reach the program. Does nothing without a window.
+    pub fn wait_for_key_press(&mut self) {
+        if self.window.is_none() {
+            return;
+        }
+
+        for pressed in &[true, false] {
+            while !self.is_window_closed() && self.is_any_key_down() != *pressed {
+                self.draw_screen();
+                sleep(KEY_POLL_INTERVAL);
+            }
+        }
+    }
+
+    // Check whether any key is held, mapped or not
+    fn is_any_key_down(&self) -> bool {
+        match self.window.as_ref().and_then(|window| window.get_keys()) {
+            Some(keys) => !keys.is_empty(),
+            None => false,
+        }
+    }
+
+    // Show a rendered buffer in the window, letterboxed if the window has a fixed size and
+    // doubled in low resolution otherwise
+    fn present(&mut self, mut buffer_32bits: Vec<u32>) {
+        let (width, height) = self.resolution();
+        let target_size = self
+            .window_size
+            .unwrap_or((usize::from(HIRES_WIDTH), usize::from(HIRES_HEIGHT)));
+        buffer_32bits = letterbox(
+            &buffer_32bits,
+            (usize::from(width), usize::from(height)),
+            target_size,
+        );
+
+        let window = match self.window.as_mut() {
+            Some(window) => window,
+            None => return,
+        };
 
+        if window.is_open() {
+            window.update_with_buffer(&buffer_32bits).unwrap();
+        }
+    }
+
+    // Get the colors to show by pixel value, see PLANE_COUNT
+    fn palette(&self) -> [u32; 4] {
+        [
+            self.background_color,
+            self.draw_color,
+            PLANE_1_COLOR,
+            BOTH_PLANES_COLOR,
+        ]
+    }
+
+    // Convert the framebuffer into colors picked by pixel value from the palette, a disabled
+    // display only shows the background
+    fn render(&self, palette: &[u32; 4]) -> Vec<u32> {
+        let mut buffer_32bits = vec![palette[0]; self.framebuffer.len()];
+
+        if self.display_enabled {
             for (pixel_index, pixel) in self.framebuffer.iter().enumerate() {
                 if *pixel > 0 {
-                    // Convert non-zero values to draw color on screen
-                    buffer_32bits[pixel_index] = DRAW_COLOR;
+                    buffer_32bits[pixel_index] = palette[usize::from(*pixel & 0b11)];
+                } else if self.persistence_counters[pixel_index] > 0 {
+                    // Pixels which just went off fade out in the first plane's color
+                    buffer_32bits[pixel_index] = palette[1];
                 }
             }
+        }
 
-            self.window.update_with_buffer(&buffer_32bits).unwrap();
-        }
-    }
-
-    // Get currently pressed key code as per key map, otherwise 0xff
-    pub fn get_current_key_code(&mut self) -> u8 {
-        let mut key_code: u8 = 0xff;
-        let keys_option = self.window.get_keys();
-
-        if keys_option.is_some() {
-            let keys = keys_option.unwrap();
-
-            if !keys.is_empty() {
-                let key = keys[0];
-
-                key_code = match key {
-                    Key::X => 0x0,
-                    Key::Key1 => 0x1,
-                    Key::Key2 => 0x2,
-                    Key::Key3 => 0x3,
-                    Key::Q => 0x4,
-                    Key::W => 0x5,
-                    Key::E => 0x6,
-                    Key::A => 0x7,
-                    Key::S => 0x8,
-                    Key::D => 0x9,
-                    Key::Z => 0xA,
-                    Key::C => 0xB,
-                    Key::Key4 => 0xC,
-                    Key::R => 0xD,
-                    Key::F => 0xE,
-                    Key::V => 0xF,
-                    _ => 0xff,
-                };
-            }
+        buffer_32bits
+    }
+
+    // Count down how long each pixel stays lit, pixels which are on start over. This hides the
+    // flicker of sprites which are erased and redrawn at the cost of some ghosting.
+    fn update_persistence(&mut self) {
+        if self.persistence <= 1 {
+            return;
+        }
+
+        for (counter, pixel) in self
+            .persistence_counters
+            .iter_mut()
+            .zip(self.framebuffer.iter())
+        {
+            *counter = if *pixel > 0 {
+                self.persistence
+            } else {
+                counter.saturating_sub(1)
+            };
+        }
+    }
+
+    // Blank or show the display without touching the framebuffer
+    pub fn set_display_enabled(&mut self, enabled: bool) {
+        self.display_enabled = enabled;
+    }
+
+    // Show lit pixels of the first plane and the background in other colors (RGB), e.g. for
+    // an amber or green phosphor look
+    pub fn set_colors(&mut self, fg: u32, bg: u32) {
+        self.draw_color = fg;
+        self.background_color = bg;
+    }
+
+    // Select the host keyboard layout used for the key map
+    pub fn set_key_layout(&mut self, key_layout: KeyLayout) {
+        self.key_map = KeyMap::from_layout(key_layout);
+    }
+
+    // Use custom key bindings, an invalid key map is rejected and the current one kept
+    pub fn set_key_map(&mut self, key_map: KeyMap) -> Result<(), KeyMapError> {
+        key_map.validate()?;
+        self.key_map = key_map;
+
+        Ok(())
+    }
+
+    // Resolve opposite keys held at the same time with a filter
+    pub fn set_socd_filter(&mut self, socd_filter: SocdFilter) {
+        self.socd_filter = socd_filter;
+    }
+
+    // Get the currently held keys as per key map after the SOCD filter, bit n is set while key
+    // n is held
+    pub fn get_current_keys(&mut self) -> u16 {
+        let keys = self
+            .window
+            .as_ref()
+            .and_then(|window| window.get_keys())
+            .unwrap_or_default();
+
+        let mut held: Vec<u8> = keys
+            .iter()
+            .map(|key| self.key_map.key_code(*key))
+            .filter(|key_code| *key_code <= 0xf)
+            .collect();
+        self.socd_filter.apply(&mut held);
+
+        held.iter().fold(0, |mask, key_code| mask | 1 << key_code)
+    }
+
+    // Check whether the fast-forward key is currently held
+    pub fn is_turbo_key_down(&self) -> bool {
+        match &self.window {
+            Some(window) => window.is_key_down(TURBO_KEY),
+            None => false,
         }
+    }
+
+    // Check whether the save state key was pressed since the last check
+    pub fn is_save_state_key_pressed(&self) -> bool {
+        self.is_key_pressed(SAVE_STATE_KEY)
+    }
 
-        key_code
+    // Check whether the load state key was pressed since the last check
+    pub fn is_load_state_key_pressed(&self) -> bool {
+        self.is_key_pressed(LOAD_STATE_KEY)
+    }
+
+    fn is_key_pressed(&self, key: Key) -> bool {
+        match &self.window {
+            Some(window) => window.is_key_pressed(key, KeyRepeat::No),
+            None => false,
+        }
     }
 
     // Start playing sound
     pub fn play_sound(&mut self) {
-        self.audio_sink.play();
+        for audio in self.audio.iter_mut() {
+            audio.play();
+        }
     }
 
     // Stop playing sound
     pub fn stop_sound(&mut self) {
-        self.audio_sink.pause();
+        for audio in self.audio.iter_mut() {
+            audio.stop();
+        }
+    }
+
+    // Add another sound output which plays alongside the existing ones
+    pub fn add_audio(&mut self, mut audio: Box<dyn Audio>) {
+        if let Some(pattern) = &self.audio_pattern {
+            audio.play_pattern(pattern, self.pattern_rate());
+        } else if self.beep_frequency != BEEP_FREQ {
+            audio.set_beep_frequency(self.beep_frequency);
+        }
+
+        self.audio.push(audio);
+    }
+
+    // Play the beep of every sound output at another frequency
+    pub fn set_beep_frequency(&mut self, hz: u32) {
+        if self.beep_frequency == hz {
+            return;
+        }
+
+        self.beep_frequency = hz;
+
+        if let Some(pattern) = self.audio_pattern {
+            self.set_audio_pattern(pattern);
+            return;
+        }
+
+        for audio in self.audio.iter_mut() {
+            audio.set_beep_frequency(hz);
+        }
+    }
+
+    // Get the frequency of the beep in Hz
+    pub fn beep_frequency(&self) -> u32 {
+        self.beep_frequency
+    }
+
+    // Play an XO-CHIP audio pattern instead of the beep on every sound output. The pattern
+    // follows the beep frequency, at the default beep it plays at the XO-CHIP default rate.
+    pub fn set_audio_pattern(&mut self, pattern: [u8; 16]) {
+        self.audio_pattern = Some(pattern);
+        let rate = self.pattern_rate();
+
+        for audio in self.audio.iter_mut() {
+            audio.play_pattern(&pattern, rate);
+        }
+    }
+
+    // Go back to playing the beep at the current frequency instead of an audio pattern
+    pub fn clear_audio_pattern(&mut self) {
+        if self.audio_pattern.take().is_none() {
+            return;
+        }
+
+        for audio in self.audio.iter_mut() {
+            audio.set_beep_frequency(self.beep_frequency);
+        }
+    }
+
+    // Get the audio pattern played instead of the beep
+    pub fn audio_pattern(&self) -> Option<[u8; 16]> {
+        self.audio_pattern
+    }
+
+    // Get the samples per second of the audio pattern for the current beep frequency
+    fn pattern_rate(&self) -> u32 {
+        (u64::from(PATTERN_RATE) * u64::from(self.beep_frequency) / u64::from(BEEP_FREQ)) as u32
+    }
+
+    // Let sound outputs know an emulated frame passed
+    pub fn end_audio_frame(&mut self) {
+        for audio in self.audio.iter_mut() {
+            audio.end_frame();
+        }
+    }
+}
+
+impl Drop for Periphery {
+    // Silence audio on every exit path, including panics, to avoid a lingering tone or click
+    fn drop(&mut self) {
+        self.stop_sound();
+    }
+}
+
+// Parse an RGB color written as RRGGBB in hex, optionally starting with '#' like in Octo
+pub fn parse_color(value: &str) -> Result<u32, String> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+
+    match u32::from_str_radix(hex, 16) {
+        Ok(color) if hex.len() == 6 => Ok(color),
+        _ => Err(format!("Invalid color {}, expected RRGGBB", value)),
+    }
+}
+
+// Get the minifb scale for a factor checked by DisplayOptions::parse_scale. The window is as
+// large as the high resolution with twice as many pixels per side, so the minifb scale is half
+// the factor, and a factor of 1 shows low resolution pixels at 2x.
+fn window_scale(factor: u8) -> minifb::Scale {
+    let factor = (factor / 2).max(1);
+
+    match WINDOW_SCALES.iter().find(|(known, _)| *known == factor) {
+        Some((_, scale)) => *scale,
+        None => panic!("Unsupported window scale: {}", factor),
+    }
+}
+
+// Scale a buffer by the largest integer factor fitting into the target size and center it,
+// the remaining area is filled with black bars
+fn letterbox(buffer: &[u32], size: (usize, usize), target_size: (usize, usize)) -> Vec<u32> {
+    let (width, height) = size;
+    let (target_width, target_height) = target_size;
+
+    let scale = (target_width / width).min(target_height / height).max(1);
+    let offset_x = target_width.saturating_sub(width * scale) / 2;
+    let offset_y = target_height.saturating_sub(height * scale) / 2;
+
+    let mut letterboxed = vec![0x00_00_00; target_width * target_height];
+
+    for (y, row) in letterboxed.chunks_mut(target_width).enumerate() {
+        if y < offset_y || (y - offset_y) / scale >= height {
+            continue;
+        }
+
+        let source_y = (y - offset_y) / scale;
+
+        for (x, pixel) in row.iter_mut().enumerate() {
+            if x < offset_x || (x - offset_x) / scale >= width {
+                continue;
+            }
+
+            *pixel = buffer[source_y * width + (x - offset_x) / scale];
+        }
+    }
+
+    letterboxed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_framebuffer() {
+        let mut periphery = Periphery::headless();
+        let mut pixels = [0; SCREEN_SIZE];
+        pixels[0] = 1;
+        pixels[SCREEN_SIZE - 1] = 1;
+
+        periphery.set_framebuffer(&pixels).unwrap();
+        let rendered = periphery.render(&periphery.palette());
+        assert_eq!(rendered[0], DRAW_COLOR);
+        assert_eq!(rendered[1], BACKGROUND_COLOR);
+        assert_eq!(rendered[SCREEN_SIZE - 1], DRAW_COLOR);
+
+        assert_eq!(
+            periphery.set_framebuffer(&[0; 10]),
+            Err(String::from("Framebuffer has 10 pixels, expected 2048"))
+        );
+        assert_eq!(periphery.framebuffer[0], 1);
+    }
+
+    #[test]
+    fn test_hires() {
+        let mut periphery = Periphery::headless();
+        periphery.framebuffer[0] = 1;
+
+        periphery.set_hires(true);
+        assert_eq!(periphery.resolution(), (128, 64));
+        assert_eq!(periphery.framebuffer.len(), HIRES_SIZE);
+        assert!(periphery.framebuffer.iter().all(|pixel| *pixel == 0));
+        assert_eq!(periphery.render(&periphery.palette()).len(), HIRES_SIZE);
+
+        periphery.set_hires(false);
+        assert_eq!(periphery.resolution(), (64, 32));
+        assert_eq!(periphery.framebuffer.len(), SCREEN_SIZE);
+    }
+
+    #[test]
+    fn test_planes() {
+        let mut periphery = Periphery::headless();
+        periphery.framebuffer[..3].copy_from_slice(&[1, 2, 3]);
+
+        let rendered = periphery.render(&periphery.palette());
+        assert_eq!(
+            rendered[..4],
+            [
+                DRAW_COLOR,
+                PLANE_1_COLOR,
+                BOTH_PLANES_COLOR,
+                BACKGROUND_COLOR
+            ]
+        );
+
+        // Only the second plane moves and gets cleared
+        periphery.set_selected_planes(2);
+        periphery.scroll_right(1);
+        assert_eq!(periphery.framebuffer[..4], [1, 0, 3, 2]);
+
+        periphery.clear_selected_planes();
+        assert_eq!(periphery.framebuffer[..4], [1, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_qwerty_key_codes() {
+        assert_eq!(KeyMap::from_layout(KeyLayout::Qwerty).key_code(Key::X), 0x0);
+        assert_eq!(
+            KeyMap::from_layout(KeyLayout::Qwerty).key_code(Key::Key4),
+            0xC
+        );
+        assert_eq!(KeyMap::from_layout(KeyLayout::Qwerty).key_code(Key::Z), 0xA);
+        assert_eq!(
+            KeyMap::from_layout(KeyLayout::Qwerty).key_code(Key::Y),
+            0xff
+        );
+    }
+
+    #[test]
+    fn test_layouts_keep_physical_position() {
+        assert_eq!(KeyMap::from_layout(KeyLayout::Qwertz).key_code(Key::Y), 0xA);
+        assert_eq!(KeyMap::from_layout(KeyLayout::Azerty).key_code(Key::A), 0x4);
+        assert_eq!(
+            KeyMap::from_layout(KeyLayout::Dvorak).key_code(Key::Apostrophe),
+            0x4
+        );
+        assert_eq!(
+            KeyMap::from_layout(KeyLayout::Colemak).key_code(Key::T),
+            0xE
+        );
+    }
+
+    #[test]
+    fn test_parse_scale() {
+        assert_eq!(DisplayOptions::parse_scale("8"), Ok(8));
+        assert_eq!(DisplayOptions::parse_scale("32"), Ok(32));
+        assert_eq!(
+            DisplayOptions::parse_scale("3"),
+            Err(String::from(
+                "Invalid window scale 3, valid scales are 1, 2, 4, 8, 16, 32"
+            ))
+        );
+        assert!(DisplayOptions::parse_scale("big").is_err());
+    }
+
+    #[test]
+    fn test_parse_window_size() {
+        assert_eq!(DisplayOptions::parse_window_size("800x600"), Ok((800, 600)));
+        assert_eq!(DisplayOptions::parse_window_size("128x64"), Ok((128, 64)));
+        assert_eq!(
+            DisplayOptions::parse_window_size("0x0"),
+            Err(String::from(
+                "Window size 0x0 is smaller than the 128x64 display"
+            ))
+        );
+        assert!(DisplayOptions::parse_window_size("800x63").is_err());
+        assert!(DisplayOptions::parse_window_size("800").is_err());
+        assert!(DisplayOptions::parse_window_size("wide").is_err());
+    }
+
+    #[test]
+    fn test_socd_filter_passes_through_without_pairs() {
+        let mut filter = SocdFilter::default();
+        let mut held = vec![0x4, 0x6];
+        filter.apply(&mut held);
+        assert_eq!(held, vec![0x4, 0x6]);
+    }
+
+    #[test]
+    fn test_socd_filter_neutral() {
+        let mut filter = SocdFilter::new(SocdMode::Neutral, SocdFilter::DIRECTION_PAIRS.to_vec());
+
+        let mut held = vec![0x4, 0x6, 0x5];
+        filter.apply(&mut held);
+        assert_eq!(held, vec![0x5]);
+
+        let mut held = vec![0x2, 0x6];
+        filter.apply(&mut held);
+        assert_eq!(held, vec![0x2, 0x6]);
+    }
+
+    #[test]
+    fn test_socd_filter_last_pressed() {
+        let mut filter =
+            SocdFilter::new(SocdMode::LastPressed, SocdFilter::DIRECTION_PAIRS.to_vec());
+
+        let mut held = vec![0x4];
+        filter.apply(&mut held);
+        assert_eq!(held, vec![0x4]);
+
+        // Right pressed while left is held wins
+        let mut held = vec![0x4, 0x6];
+        filter.apply(&mut held);
+        assert_eq!(held, vec![0x6]);
+
+        // Releasing and pressing left again makes it win
+        let mut held = vec![0x6];
+        filter.apply(&mut held);
+        let mut held = vec![0x6, 0x4];
+        filter.apply(&mut held);
+        assert_eq!(held, vec![0x4]);
+    }
+
+    #[test]
+    fn test_key_map_validation() {
+        for layout in &[
+            KeyLayout::Qwerty,
+            KeyLayout::Qwertz,
+            KeyLayout::Azerty,
+            KeyLayout::Dvorak,
+            KeyLayout::Colemak,
+        ] {
+            assert_eq!(KeyMap::from_layout(*layout).validate(), Ok(()));
+        }
+
+        // Aliases are fine
+        let mut key_map = KeyMap::from_layout(KeyLayout::Qwerty);
+        key_map.bind(Key::Up, 0x5);
+        assert_eq!(key_map.validate(), Ok(()));
+        assert_eq!(key_map.key_code(Key::Up), 0x5);
+        assert_eq!(key_map.key_code(Key::W), 0x5);
+
+        key_map.bind(Key::Up, 0x8);
+        assert_eq!(
+            key_map.validate(),
+            Err(KeyMapError::Conflict {
+                key: Key::Up,
+                key_codes: (0x5, 0x8)
+            })
+        );
+
+        let mut key_map = KeyMap::from_layout(KeyLayout::Qwerty);
+        key_map.bind(Key::Up, 0x10);
+        assert!(key_map.validate().is_err());
+    }
+
+    #[test]
+    fn test_invalid_key_map_is_rejected() {
+        let mut periphery = Periphery::headless();
+        let mut key_map = KeyMap::from_layout(KeyLayout::Qwerty);
+        key_map.bind(Key::Up, 0x10);
+
+        assert_eq!(
+            periphery.set_key_map(key_map),
+            Err(KeyMapError::InvalidKeyCode {
+                key: Key::Up,
+                key_code: 0x10
+            })
+        );
+        assert_eq!(periphery.key_map, KeyMap::from_layout(KeyLayout::Qwerty));
+        assert_eq!(periphery.get_current_keys(), 0);
+    }
+
+    #[test]
+    fn test_disabled_display_renders_background() {
+        let mut periphery = Periphery::headless();
+        periphery.framebuffer[0] = 1;
+        assert_eq!(periphery.render(&periphery.palette())[0], DRAW_COLOR);
+
+        periphery.set_display_enabled(false);
+        assert!(periphery
+            .render(&periphery.palette())
+            .iter()
+            .all(|color| *color == BACKGROUND_COLOR));
+        assert_eq!(periphery.framebuffer[0], 1);
+    }
+
+    #[test]
+    fn test_colors() {
+        let mut periphery = Periphery::headless();
+        periphery.framebuffer[0] = 1;
+        assert_eq!(
+            periphery.render(&periphery.palette())[..2],
+            [DRAW_COLOR, BACKGROUND_COLOR]
+        );
+
+        periphery.set_colors(0xff_b0_00, 0x28_18_00);
+        assert_eq!(
+            periphery.render(&periphery.palette())[..2],
+            [0xff_b0_00, 0x28_18_00]
+        );
+
+        assert_eq!(parse_color("#FFB000"), Ok(0xff_b0_00));
+        assert_eq!(parse_color("33ff66"), Ok(0x33_ff_66));
+        assert!(parse_color("#FFF").is_err());
+        assert!(parse_color("amber!").is_err());
+    }
+
+    #[test]
+    fn test_pixel_persistence() {
+        let mut periphery = Periphery::headless();
+        periphery.persistence = 3;
+        periphery.framebuffer[0] = 1;
+        periphery.update_persistence();
+
+        // Lit in the frame it was on and the two following ones
+        periphery.framebuffer[0] = 0;
+        for _ in 0..2 {
+            periphery.update_persistence();
+            assert_eq!(periphery.render(&periphery.palette())[0], DRAW_COLOR);
+        }
+
+        periphery.update_persistence();
+        assert_eq!(periphery.render(&periphery.palette())[0], BACKGROUND_COLOR);
+    }
+
+    #[test]
+    fn test_letterbox() {
+        let mut buffer = vec![0x11_11_11; 4 * 2];
+        buffer[0] = 0xff_ff_ff;
+
+        let letterboxed = letterbox(&buffer, (4, 2), (10, 7));
+
+        // Scale of 2 leaves a one pixel bar on the left/right and top/bottom
+        assert_eq!(letterboxed.len(), 70);
+        assert_eq!(letterboxed[0], 0x00_00_00);
+        assert_eq!(letterboxed[10 + 1], 0xff_ff_ff);
+        assert_eq!(letterboxed[2 * 10 + 2], 0xff_ff_ff);
+        assert_eq!(letterboxed[10 + 3], 0x11_11_11);
+        assert_eq!(letterboxed[10 + 9], 0x00_00_00);
+        assert_eq!(letterboxed[5 * 10 + 5], 0x00_00_00);
     }
 }
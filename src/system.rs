@@ -1,45 +1,661 @@
+use crate::audio::BEEP_FREQ;
 use crate::bin::*;
-use crate::periphery::{Periphery, SCREEN_HEIGHT, SCREEN_SIZE, SCREEN_WIDTH};
+use crate::disassembler::disassemble_opcode_labeled;
+use crate::instruction::{decode, Instruction};
+use crate::labels::Labels;
+use crate::periphery::{KeyLayout, Periphery, SocdFilter, HIRES_SIZE, PLANE_COUNT, SCREEN_SIZE};
 
+use std::collections::{BTreeSet, HashSet, VecDeque};
 use std::convert::TryInto;
-use std::ops::Add;
-use std::thread::sleep;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::{Add, Range};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, sleep};
 use std::time::{Duration, Instant};
 
-use rand::Rng;
+use log::{error, info, trace, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
 use std::ops::Sub;
 
 const MEMORY_SIZE: usize = 4_096;
-const TARGET_FPS: u32 = 60;
-const CPU_CLOCK_IN_HZ: u32 = 1_000;
+const DEFAULT_FPS: u32 = 60;
+// The delay and sound timers always count down at 60Hz, independent of the frame rate
+const TIMER_HZ: u32 = 60;
+const DEFAULT_CLOCK_IN_HZ: u32 = 1_000;
+const MAX_CLOCK_IN_HZ: u32 = 10_000;
+// Slowest clock accepted from users, anything below is almost certainly a typo
+const MIN_USER_CLOCK_IN_HZ: u32 = 100;
+const TIMER_INTERVAL: Duration = Duration::from_nanos(1_000_000_000 / TIMER_HZ as u64);
+// Machine cycles of the COSMAC VIP per 60Hz frame, a 1.7609 MHz clock with 8 clocks per
+// machine cycle
+const VIP_CYCLES_PER_TICK: u32 = 3_668;
+// Machine cycles of a VIP draw without rows, and per byte of the sprite drawn
+const VIP_DRAW_CYCLES: u32 = 22;
+const VIP_DRAW_BYTE_CYCLES: u32 = 34;
 
-const CYCLES_PER_FRAME: u32 = CPU_CLOCK_IN_HZ / TARGET_FPS;
-const TIMER_INTERVAL: Duration = Duration::from_nanos(1_000_000_000 / 60);
-const FRAME_INTERVAL: Duration = Duration::from_nanos(1_000_000_000 / TARGET_FPS as u64);
+// Nesting depth of subroutine calls on the original interpreter
+const DEFAULT_STACK_SIZE: usize = 16;
+
+// Cycles the pause prompt runs at most while waiting for the next draw
+const PROMPT_DRAW_CYCLES: u64 = 1_000_000;
+
+// How often the pause prompt checks for another interrupt while waiting for an answer
+const PROMPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Columns 00FB and 00FC scroll the display by
+const HORIZONTAL_SCROLL_COLUMNS: usize = 4;
 
 const FONTSET_OFFSET: u16 = 0x50;
+const FONTSET_SIZE: usize = 80;
+
+// SUPER-CHIP digits 0 to 9, 10 bytes each, stored right after the fontset
+const LARGE_FONTSET_OFFSET: u16 = FONTSET_OFFSET + FONTSET_SIZE as u16;
+const LARGE_FONTSET_SIZE: usize = 100;
+
+// Number of HP-48 flag registers FX75/FX85 store to and load from
+const FLAG_REGISTERS: usize = 8;
+
+// Bytes of an XO-CHIP audio pattern, one bit per sample
+const AUDIO_PATTERN_SIZE: usize = 16;
+
+// XO-CHIP pitch playing the default beep
+const DEFAULT_PITCH: u8 = 64;
+
+// How to treat the legacy 0NNN "call machine code" opcode
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MachineCodePolicy {
+    // Treat as unknown opcode
+    Strict,
+    // Skip the instruction
+    #[default]
+    Ignore,
+    // Stop executing instructions
+    Halt,
+}
+
+// What to do when a 2NNN call exceeds the stack size
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StackOverflowPolicy {
+    // Halt with an error
+    #[default]
+    Error,
+    // Discard the oldest return address to make room
+    Wrap,
+    // Skip the call
+    Ignore,
+}
+
+// What to do when an opcode does not decode to any instruction
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UnknownOpcodePolicy {
+    // Halt with an error
+    #[default]
+    Halt,
+    // Log the opcode and continue with the next instruction
+    Skip,
+}
+
+// How to treat draws with a height of 0 (DXY0), which draw nothing on the original interpreter
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ZeroHeightDrawPolicy {
+    // Draw nothing and clear VF
+    #[default]
+    Empty,
+    // Halt with an error, the opcode is meaningless in base CHIP-8
+    Error,
+    // Draw a 16x16 sprite from 32 bytes at I like SUPER-CHIP
+    Large,
+}
+
+// Interpreter whose quirks a ROM expects
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Platform {
+    // COSMAC VIP: shifts use VY, FX55/FX65 advance I and draws wait for the next frame
+    Chip8,
+    // SUPER-CHIP 1.1: shifts in place, FX55/FX65 leave I unchanged and BXNN jumps to XNN + VX
+    SuperChip,
+    // XO-CHIP as in Octo: shifts use VY, FX55/FX65 advance I and draws do not wait
+    XoChip,
+}
+
+impl Platform {
+    // Parse a platform name as used by Octo and the CHIP-8 database, ignoring case
+    pub fn parse(value: &str) -> Result<Platform, String> {
+        match value.to_lowercase().as_str() {
+            "chip8" | "chip-8" | "originalchip8" => Ok(Platform::Chip8),
+            "schip" | "superchip" | "super-chip" => Ok(Platform::SuperChip),
+            "xochip" | "xo-chip" => Ok(Platform::XoChip),
+            _ => Err(format!("Unknown platform: {}", value)),
+        }
+    }
+}
+
+// Why an instruction could not be executed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmulationError {
+    // No instruction has this opcode, or it is disabled by a policy
+    UnknownOpcode {
+        opcode: u16,
+        pc: usize,
+    },
+    // The instruction would access memory from start to end beyond the 4KB address space
+    MemoryOutOfBounds {
+        opcode: u16,
+        pc: usize,
+        start: usize,
+        end: usize,
+    },
+    // A call exceeded the stack size
+    StackOverflow {
+        pc: usize,
+        stack_size: usize,
+    },
+    // A return without a call
+    StackUnderflow {
+        pc: usize,
+    },
+    // The program counter left the executable region from start to end
+    OutsideExecutableRegion {
+        pc: usize,
+        start: usize,
+        end: usize,
+    },
+    // A draw with height 0 while those are treated as errors
+    ZeroHeightDraw {
+        opcode: u16,
+        pc: usize,
+    },
+    // FX30 with a VX the large font has no digit for
+    InvalidLargeDigit {
+        opcode: u16,
+        pc: usize,
+        digit: u8,
+    },
+}
+
+impl fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulationError::UnknownOpcode { opcode, pc } => {
+                write!(f, "Unknown opcode: {:#X} at address {:#X}!", opcode, pc)
+            }
+            EmulationError::MemoryOutOfBounds {
+                opcode,
+                pc,
+                start,
+                end,
+            } => write!(
+                f,
+                "Opcode {:#X} at address {:#X} accesses memory {:#X}..{:#X} beyond the end of memory!",
+                opcode, pc, start, end
+            ),
+            EmulationError::StackOverflow { pc, stack_size } => write!(
+                f,
+                "Stack overflow: call at address {:#X} exceeds {} levels!",
+                pc, stack_size
+            ),
+            EmulationError::StackUnderflow { pc } => write!(
+                f,
+                "Stack underflow: return at address {:#X} without a call!",
+                pc
+            ),
+            EmulationError::OutsideExecutableRegion { pc, start, end } => write!(
+                f,
+                "Program counter {:#X} left the executable region {:#X}..{:#X}!",
+                pc, start, end
+            ),
+            EmulationError::ZeroHeightDraw { opcode, pc } => write!(
+                f,
+                "Zero height draw: {:#X} at address {:#X}!",
+                opcode, pc
+            ),
+            EmulationError::InvalidLargeDigit { opcode, pc, digit } => write!(
+                f,
+                "Invalid large digit: {:#X} at address {:#X} selects digit {}!",
+                opcode, pc, digit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EmulationError {}
+
+// How often and to what values the timers were set
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TimerStats {
+    pub delay_timer_sets: u64,
+    pub delay_timer_max: u8,
+    pub sound_timer_sets: u64,
+    pub sound_timer_max: u8,
+}
+
+impl fmt::Display for TimerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "delay timer set {} times, max value {}; sound timer set {} times, max value {}",
+            self.delay_timer_sets,
+            self.delay_timer_max,
+            self.sound_timer_sets,
+            self.sound_timer_max
+        )
+    }
+}
+
+// Wall-clock intervals between the starts of displayed frames, to see how well the run loop
+// keeps its pace
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    pub frames: u64,
+    pub target: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    total_seconds: f64,
+    total_squared_seconds: f64,
+}
+
+impl FrameStats {
+    // Add the interval since the previous frame started
+    fn record(&mut self, interval: Duration) {
+        if self.frames == 0 || interval < self.min {
+            self.min = interval;
+        }
+        self.max = self.max.max(interval);
+        self.frames += 1;
+
+        let seconds = interval.as_secs_f64();
+        self.total_seconds += seconds;
+        self.total_squared_seconds += seconds * seconds;
+    }
+
+    // Get the mean interval
+    pub fn average(&self) -> Duration {
+        if self.frames == 0 {
+            return Duration::default();
+        }
+
+        Duration::from_secs_f64(self.total_seconds / self.frames as f64)
+    }
+
+    // Get the standard deviation of the intervals, which is the jitter
+    pub fn standard_deviation(&self) -> Duration {
+        if self.frames == 0 {
+            return Duration::default();
+        }
+
+        let mean = self.total_seconds / self.frames as f64;
+        let variance = self.total_squared_seconds / self.frames as f64 - mean * mean;
+        Duration::from_secs_f64(variance.max(0.0).sqrt())
+    }
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let milliseconds = |duration: Duration| duration.as_secs_f64() * 1_000.0;
+
+        write!(
+            f,
+            "{} frame intervals: min {:.2}ms, max {:.2}ms, avg {:.2}ms, stddev {:.2}ms (target {:.2}ms)",
+            self.frames,
+            milliseconds(self.min),
+            milliseconds(self.max),
+            milliseconds(self.average()),
+            milliseconds(self.standard_deviation()),
+            milliseconds(self.target)
+        )
+    }
+}
+
+// Named range of addresses in the 4KB address space
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryRegion {
+    pub name: &'static str,
+    pub range: Range<usize>,
+}
+
+// Layout of memory and stack usage at a point in time
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryMap {
+    pub regions: Vec<MemoryRegion>,
+    pub stack_depth: usize,
+    pub stack_size: usize,
+}
+
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for region in &self.regions {
+            writeln!(
+                f,
+                "0x{:03X}-0x{:03X} {:>5} bytes  {}",
+                region.range.start,
+                region.range.end - 1,
+                region.range.len(),
+                region.name
+            )?;
+        }
+
+        write!(
+            f,
+            "stack: {} of {} levels in use (kept outside of memory)",
+            self.stack_depth, self.stack_size
+        )
+    }
+}
+
+// Machine state for external debuggers, serialized by System::state_json and restored by
+// System::load_state_json. The schema is versioned, fields are only ever added within a version:
+//   version         schema version, currently 1
+//   pc, i           program counter and index register
+//   sp              number of return addresses on the stack
+//   stack           return addresses, bottom first
+//   v               registers V0 to VF
+//   delay_timer     delay timer value
+//   sound_timer     sound timer value
+//   memory          all 4096 bytes of memory, base64-encoded
+#[derive(Debug, Deserialize, Serialize)]
+struct StateSnapshot {
+    version: u32,
+    pc: usize,
+    i: u16,
+    sp: usize,
+    stack: Vec<usize>,
+    v: [u8; 16],
+    delay_timer: u8,
+    sound_timer: u8,
+    memory: String,
+}
+
+// Save states written by System::save_state start with this magic and a format version byte,
+// followed by (numbers big-endian):
+//   pc, i           2 bytes each
+//   delay, sound    timer values, 1 byte each
+//   v               registers V0 to VF, 16 bytes
+//   stack           number of return addresses (2 bytes), then 2 bytes per address, bottom first
+//   hires           1 if the 128x64 resolution is active, otherwise 0
+//   planes          XO-CHIP planes selected with FN01, 1 byte from 0 to 3
+//   pitch           XO-CHIP pitch register, 1 byte
+//   pattern         1 if an XO-CHIP audio pattern is loaded followed by its 16 bytes, otherwise 0
+//   flags           the 8 SUPER-CHIP flag registers
+//   memory          all 4096 bytes
+//   framebuffer     one byte per pixel of the active resolution
+const SAVE_STATE_MAGIC: &[u8] = b"C8ST";
+const SAVE_STATE_VERSION: u8 = 2;
+
+// Reads a save state front to back, running out of data is an invalid state
+struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], LoadError> {
+        if self.data.len() < length {
+            return Err(LoadError::InvalidState(String::from("state is truncated")));
+        }
+
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn word(&mut self) -> Result<u16, LoadError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+// Why a state or data could not be loaded into memory
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadError {
+    // The state is malformed, from another schema version or describes an impossible machine
+    InvalidState(String),
+    // The data does not fit into memory at the requested offset
+    TooLarge { size: usize, available: usize },
+    // The data would overwrite the fontset while it is write protected
+    FontsetOverlap { offset: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::InvalidState(reason) => write!(f, "Invalid state: {}", reason),
+            LoadError::TooLarge { size, available } => write!(
+                f,
+                "Data of {} bytes does not fit into memory, only {} bytes are available",
+                size, available
+            ),
+            LoadError::FontsetOverlap { offset } => write!(
+                f,
+                "Refusing to load data at {:#X} which would overwrite the fontset",
+                offset
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+// Breakpoint condition which fires once a register holds a value after an instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegisterCondition {
+    pub register: usize,
+    pub value: u8,
+}
+
+impl fmt::Display for RegisterCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "V{:X} == {:#04X}", self.register, self.value)
+    }
+}
+
+// Subroutine calls observed during a run as (caller address, callee address) pairs
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CallGraph {
+    pub calls: BTreeSet<(usize, usize)>,
+}
+
+impl CallGraph {
+    // Render the graph in Graphviz DOT format
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph calls {\n");
+
+        for (caller, callee) in &self.calls {
+            dot.push_str(&format!(
+                "    \"0x{:03X}\" -> \"0x{:03X}\";\n",
+                caller, callee
+            ));
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+// Assertions signalled by a self-testing ROM in test mode
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TestResults {
+    // The ROM signalled success with 0F00
+    pub passed: bool,
+
+    // Address and code of every failed assertion 0FNN
+    pub failures: Vec<(usize, u8)>,
+}
+
+// Instruction passed to the step observer after it was executed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StepInfo {
+    // Address the instruction was fetched from
+    pub address: usize,
+
+    // Opcode which was executed, after any on_fetch replacement
+    pub opcode: u16,
+
+    // Program counter after executing the instruction
+    pub next_address: usize,
+
+    // Frame the instruction was executed in
+    pub frame: u64,
+}
+
+// Observer called with every executed instruction
+type StepObserver = Box<dyn FnMut(&StepInfo)>;
 
 pub struct System {
     program_counter: usize,
     memory: [u8; MEMORY_SIZE],
 
-    stack: [usize; 25],
-    stack_pointer: usize,
+    // Return addresses, the last entry is the top of the stack
+    stack: Vec<usize>,
+    stack_size: usize,
+    stack_overflow_policy: StackOverflowPolicy,
+    unknown_opcode_policy: UnknownOpcodePolicy,
+
+    // Panic on errors instead of halting, for hard failures in tests and scripts
+    strict: bool,
+    zero_height_draw_policy: ZeroHeightDrawPolicy,
+
+    // 8XY6/8XYE shift VY into VX like the COSMAC VIP instead of shifting VX in place
+    quirk_shift_uses_vy: bool,
+
+    // FX55/FX65 advance the index register past the last register like the COSMAC VIP
+    quirk_memory_increments_index: bool,
+
+    // BXNN jumps to XNN + VX like SUPER-CHIP instead of BNNN jumping to NNN + V0
+    quirk_jump_uses_vx: bool,
+
+    // Draws wait for the start of the next frame, and whether the current draw already waited
+    quirk_display_wait: bool,
+    waited_for_display: bool,
+
+    // Count VIP machine cycles per instruction against a VIP frame instead of instructions
+    vip_timing: bool,
+    // Cycles of the current frame used by the last instruction
+    cycle_cost: u32,
+    // Bits 8XY6/8XYE shift by, always 1 in every known CHIP-8 variant
+    shift_amount: u8,
 
     v_registers: [u8; 16],
     index_register: u16,
 
+    // Flag registers of the HP-48 SUPER-CHIP ran on, kept in a file if flags_path is set so
+    // high scores survive restarts
+    rpl_flags: [u8; FLAG_REGISTERS],
+    flags_path: Option<PathBuf>,
+
+    // XO-CHIP pitch register set by FX3A
+    pitch: u8,
+
     delay_timer: u8,
     sound_timer: u8,
+    timer_stats: TimerStats,
+    frame_stats: FrameStats,
+    boot_delay: Duration,
+    start_paused: bool,
+    last_frame_start: Option<Instant>,
+
+    // Frames the current beep keeps playing, at least min_beep_ticks once a beep started
+    beep_ticks: u8,
+    min_beep_ticks: u8,
 
-    // Strictly speaking this would be a 'u4'
-    keyboard_input: u8,
+    // Input is sampled once per frame. A key is held while it is present in the sample
+    // (Ex9E/ExA1 look at this), pressed in the first sample it is held in (Fx0A waits for
+    // this) and released in the first sample it is missing from again. OS auto-repeat does
+    // not matter as a held key stays present in every sample.
+    // Any number of keys can be held at once, bit n is set while key n is held.
+    keyboard_input: u16,
+
+    // Held keys which were pressed and Fx0A did not consume the press of yet
+    pressed_keys: u16,
+
+    // Key Fx0A saw pressed and now waits to be released before storing it
+    waiting_key: Option<u8>,
+
+    // Keys sampled in the last frames which are not visible to the program yet
+    input_queue: VecDeque<u16>,
 
     // Helper structures for simulation
+    frame_count: u64,
+    clock_hz: u32,
+    // Displayed frames per second, 0 runs frames back to back
+    fps: u32,
+    // Frames left undrawn after each drawn one, and how many were skipped since the last draw
+    frame_skip: u32,
+    skipped_frames: u32,
     cycles_in_current_frame: u32,
+
+    // Sprites drawn in total and in the current frame and how many are allowed per frame,
+    // unlimited if None
+    draw_count: u64,
+    draws_in_current_frame: u32,
+    max_draws_per_frame: Option<u32>,
+    turbo: bool,
+    halted: bool,
     next_frame_tick: Instant,
     next_timer_tick: Instant,
 
+    // Addresses written by the last load
+    loaded_range: Option<Range<usize>>,
+
+    // Refuse loads which would overwrite the fontset instead of only warning
+    fontset_write_protection: bool,
+
+    machine_code_policy: MachineCodePolicy,
+
+    // Treat 0FNN as test assertions
+    test_mode: bool,
+    test_results: TestResults,
+
+    // Addresses the program counter may execute from, unchecked if None
+    executable_range: Option<Range<usize>>,
+
+    // Warn once per address when executing from an odd offset relative to 0x200
+    check_alignment: bool,
+    misaligned_addresses: HashSet<usize>,
+
+    // Report every 00E0 and flash the screen content before clearing it
+    debug_clears: bool,
+
+    // Names of addresses used when tracing
+    labels: Labels,
+
+    // Receives every distinct opcode the first time it is executed
+    trace_unique: Option<Box<dyn Write>>,
+    seen_opcodes: HashSet<u16>,
+
+    // Called with address and opcode after every fetch, returns the opcode to execute instead
+    on_fetch: Option<Box<dyn FnMut(usize, u16) -> u16>>,
+
+    // Subroutine calls, not recorded if None
+    call_graph: Option<CallGraph>,
+
+    // Conditions stopping run_until_breakpoint
+    register_conditions: Vec<RegisterCondition>,
+
+    // Number of interrupt requests (Ctrl-C) not handled yet, the run loop pauses if non-zero
+    interrupts: Option<Arc<AtomicUsize>>,
+
+    // Called after every executed instruction
+    on_step: Option<StepObserver>,
+
+    // Error which halted the system
+    error: Option<EmulationError>,
+
+    // Receives a CSV row of the registers at the end of every frame
+    register_log: Option<Box<dyn Write>>,
+
+    // File written and read by the save and load state keys
+    state_path: Option<PathBuf>,
+
     // Peripherials
     periphery: Periphery,
 }
@@ -47,7 +663,43 @@ pub struct System {
 impl Default for System {
     // Initialize system state, load bitfont and set program counter to 0x200 as per convention
     fn default() -> System {
-        let fontset: [u8; 80] = [
+        System::with_periphery(Periphery::default())
+    }
+}
+
+impl System {
+    // Create a system without window and audio device, for tests and batch runs
+    pub fn headless() -> System {
+        System::with_periphery(Periphery::headless())
+    }
+
+    // Initialize system around the given periphery with a fixed pattern in memory outside the
+    // fontset, like a cold boot
+    pub fn with_uninitialized_memory(periphery: Periphery, fill: u8) -> System {
+        let mut system = System::with_periphery(periphery);
+        system.fill_memory(fill);
+        system
+    }
+
+    // Initialize system around the given periphery with reproducible random garbage in memory
+    // outside the fontset
+    pub fn with_random_memory(periphery: Periphery, seed: u64) -> System {
+        let mut system = System::with_periphery(periphery);
+        system.fill_memory_randomly(seed);
+        system
+    }
+
+    // Initialize system running the given number of instructions per second, see
+    // set_clock_speed
+    pub fn with_clock_speed(hz: u32) -> System {
+        let mut system = System::default();
+        system.set_clock_speed(hz);
+        system
+    }
+
+    // Initialize system state around the given periphery
+    pub fn with_periphery(periphery: Periphery) -> System {
+        let fontset: [u8; FONTSET_SIZE] = [
             0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
             0x20, 0x60, 0x20, 0x20, 0x70, // 1
             0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
@@ -66,446 +718,4043 @@ impl Default for System {
             0xF0, 0x80, 0xF0, 0x80, 0x80, // F
         ];
 
+        let large_fontset: [u8; LARGE_FONTSET_SIZE] = [
+            0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+            0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+            0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+            0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+        ];
+
         let mut system = System {
             program_counter: 0x200,
             memory: [0; MEMORY_SIZE],
 
-            stack: [0; 25],
-            stack_pointer: 0,
+            stack: Vec::with_capacity(DEFAULT_STACK_SIZE),
+            stack_size: DEFAULT_STACK_SIZE,
+            stack_overflow_policy: StackOverflowPolicy::default(),
+            unknown_opcode_policy: UnknownOpcodePolicy::default(),
+            strict: false,
+            zero_height_draw_policy: ZeroHeightDrawPolicy::default(),
+            quirk_shift_uses_vy: false,
+            quirk_memory_increments_index: false,
+            quirk_jump_uses_vx: false,
+            quirk_display_wait: false,
+            waited_for_display: false,
+            vip_timing: false,
+            cycle_cost: 1,
+            shift_amount: 1,
 
             v_registers: [0; 16],
             index_register: 0,
 
+            rpl_flags: [0; FLAG_REGISTERS],
+            flags_path: None,
+            pitch: DEFAULT_PITCH,
+
             delay_timer: 0,
             sound_timer: 0,
+            timer_stats: TimerStats::default(),
+            frame_stats: FrameStats::default(),
+            boot_delay: Duration::default(),
+            start_paused: false,
+            last_frame_start: None,
+            beep_ticks: 0,
+            min_beep_ticks: 0,
 
             keyboard_input: 0,
+            pressed_keys: 0,
+            waiting_key: None,
+            input_queue: VecDeque::new(),
 
             next_timer_tick: Instant::now(),
             next_frame_tick: Instant::now(),
+            frame_count: 0,
+            clock_hz: DEFAULT_CLOCK_IN_HZ,
+            fps: DEFAULT_FPS,
+            frame_skip: 0,
+            skipped_frames: 0,
             cycles_in_current_frame: 0,
-            periphery: Periphery::default(),
+            draw_count: 0,
+            draws_in_current_frame: 0,
+            max_draws_per_frame: None,
+            turbo: false,
+            halted: false,
+            loaded_range: None,
+            fontset_write_protection: false,
+            machine_code_policy: MachineCodePolicy::default(),
+            test_mode: false,
+            test_results: TestResults::default(),
+            executable_range: None,
+            debug_clears: false,
+            labels: Labels::default(),
+            trace_unique: None,
+            seen_opcodes: HashSet::new(),
+            check_alignment: false,
+            misaligned_addresses: HashSet::new(),
+            on_fetch: None,
+            call_graph: None,
+            register_conditions: vec![],
+            interrupts: None,
+            on_step: None,
+            error: None,
+            register_log: None,
+            state_path: None,
+            periphery,
         };
 
         // Copy fontset with offset
-        let mut position: usize = usize::from(FONTSET_OFFSET);
-        for data in fontset.iter() {
-            system.memory[position] = *data;
-            position += 1;
-        }
+        let position: usize = usize::from(FONTSET_OFFSET);
+        system.memory[position..position + FONTSET_SIZE].copy_from_slice(&fontset);
+        let position: usize = usize::from(LARGE_FONTSET_OFFSET);
+        system.memory[position..position + LARGE_FONTSET_SIZE].copy_from_slice(&large_fontset);
 
         system
     }
-}
 
-impl System {
-    // Load data
-    pub fn copy_buffer_to_memory(&mut self, buffer: Vec<u8>, offset: usize) {
-        if buffer.len() + offset <= MEMORY_SIZE {
-            let mut counter = offset;
-            for data in buffer {
-                self.memory[counter] = data;
-                counter += 1;
-            }
-        } else {
-            panic!("You tried to load a data file which does not fit into memory!")
-        }
+    // Overwrite all memory outside the fontset with a fixed pattern
+    pub fn fill_memory(&mut self, fill: u8) {
+        self.fill_uninitialized_memory(|| fill);
     }
 
-    // Enter main run loop (blocks)
-    pub fn run(&mut self) {
-        loop {
-            // Limit maximum number of cycles per frame
-            if self.cycles_in_current_frame < CYCLES_PER_FRAME {
-                self.cycle();
-                self.cycles_in_current_frame += 1;
-            } else {
-                self.get_input();
-                self.tick_frame();
-                self.tick_timers();
-                self.sleep_if_needed();
+    // Overwrite all memory outside the fontset with reproducible random garbage
+    pub fn fill_memory_randomly(&mut self, seed: u64) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        self.fill_uninitialized_memory(|| rng.gen());
+    }
+
+    // Overwrite all memory outside the fontset with the given byte source
+    fn fill_uninitialized_memory<F: FnMut() -> u8>(&mut self, mut next_byte: F) {
+        let fontset_start = usize::from(FONTSET_OFFSET);
+        let fontset_end = usize::from(LARGE_FONTSET_OFFSET) + LARGE_FONTSET_SIZE;
+
+        for (address, data) in self.memory.iter_mut().enumerate() {
+            if address < fontset_start || address >= fontset_end {
+                *data = next_byte();
             }
         }
     }
 
-    // Execute cycle
-    #[allow(clippy::cognitive_complexity)]
-    fn cycle(&mut self) {
-        // Get current op code
-        let upper = u16::from(self.memory[self.program_counter]) << 8;
-        let lower = u16::from(self.memory[self.program_counter + 1]);
-        let opcode: u16 = upper | lower;
-
-        // Register macros
-        macro_rules! second_nibble_register {
-            () => {
-                self.v_registers[to_usize(second_nibble(opcode))]
-            };
-        }
+    // Get the active display width and height in pixels
+    pub fn resolution(&self) -> (u16, u16) {
+        self.periphery.resolution()
+    }
 
-        macro_rules! third_nibble_register {
-            () => {
-                self.v_registers[to_usize(third_nibble(opcode))]
-            };
-        }
+    // Get how the timers were used so far
+    pub fn timer_stats(&self) -> TimerStats {
+        self.timer_stats
+    }
 
-        // The big opcode matcher
-        match first_nibble(opcode) {
-            0x0 => match opcode {
-                0xE0 => {
-                    // Clear screen
-                    self.periphery.framebuffer = [0; SCREEN_SIZE];
-                    self.program_counter += 2;
-                }
-                0xEE => {
-                    // Return from subroutine
-                    self.program_counter = self.stack[self.stack_pointer];
-                    self.stack_pointer -= 1;
-                }
-                _ => {
-                    // Call program in lower three nibbles, ignored
-                    self.program_counter += 2;
-                }
-            },
-            0x1 => {
-                // Jump to lower three nibbles
-                self.program_counter = to_usize(lower_three(opcode));
-            }
-            0x2 => {
-                // Call subroutine at lower three nibbles
-                self.stack_pointer += 1;
-                self.stack[self.stack_pointer] = self.program_counter + 2;
-                self.program_counter = to_usize(lower_three(opcode));
-            }
-            0x3 => {
-                // Skip next instruction if second nibble register equals lower half
-                let equals: bool = second_nibble_register!() == to_byte(lower_half(opcode));
-
-                if equals {
-                    self.program_counter += 4;
-                } else {
-                    self.program_counter += 2;
-                }
-            }
-            0x4 => {
-                // Skip next instruction if second nibble register does not equal lower half
-                let equals: bool = second_nibble_register!() == to_byte(lower_half(opcode));
+    // Get the measured intervals between displayed frames so far
+    pub fn frame_stats(&self) -> FrameStats {
+        FrameStats {
+            target: self.frame_interval(),
+            ..self.frame_stats
+        }
+    }
 
-                if !equals {
-                    self.program_counter += 4;
-                } else {
-                    self.program_counter += 2;
-                }
-            }
-            0x5 => match fourth_nibble(opcode) {
-                0x0 => {
-                    // Skip next instruction if second nibble register equals third nibble register
-                    let equals: bool = second_nibble_register!() == third_nibble_register!();
+    // Describe where the fontset, the loaded ROM and free memory live
+    pub fn memory_map(&self) -> MemoryMap {
+        let fontset_start = usize::from(FONTSET_OFFSET);
+        let fontset_end = fontset_start + FONTSET_SIZE;
+        let large_fontset_end = fontset_end + LARGE_FONTSET_SIZE;
 
-                    if equals {
-                        self.program_counter += 4;
-                    } else {
-                        self.program_counter += 2;
-                    }
-                }
-                _ => self.panic_unknown_opcode(opcode),
+        let mut regions = vec![
+            MemoryRegion {
+                name: "reserved for the interpreter",
+                range: 0..fontset_start,
             },
-            0x6 => {
-                // Set second nibble register to lower half
-                second_nibble_register!() = to_byte(lower_half(opcode));
-                self.program_counter += 2;
-            }
-            0x7 => {
-                // Adds lower half to second nibble register (does not affect carry flag)
-                second_nibble_register!() =
-                    second_nibble_register!().wrapping_add(to_byte(lower_half(opcode)));
-                self.program_counter += 2;
-            }
-            0x8 => match fourth_nibble(opcode) {
-                0x0 => {
-                    // Set second nibble register to third nibble register
-                    second_nibble_register!() = third_nibble_register!();
-                    self.program_counter += 2;
-                }
-                0x1 => {
-                    // OR second nibble register with third nibble register
-                    second_nibble_register!() =
-                        second_nibble_register!() | third_nibble_register!();
-                    self.program_counter += 2;
-                }
-                0x2 => {
-                    // AND second nibble register with third nibble register
-                    second_nibble_register!() =
-                        second_nibble_register!() & third_nibble_register!();
-                    self.program_counter += 2;
-                }
-                0x3 => {
-                    // XOR second nibble register with third nibble register
-                    second_nibble_register!() =
-                        second_nibble_register!() ^ third_nibble_register!();
-                    self.program_counter += 2;
-                }
-                0x4 => {
-                    // Add third nibble register to second nibble register, set carry
-                    let (result, wrapped) =
-                        second_nibble_register!().overflowing_add(third_nibble_register!());
-                    self.v_registers[15] = if wrapped { 1 } else { 0 };
-                    second_nibble_register!() = result;
-                    self.program_counter += 2;
-                }
-                0x5 => {
-                    // Subtract third nibble register from second nibble register, set borrow
-                    let (result, wrapped) =
-                        second_nibble_register!().overflowing_sub(third_nibble_register!());
-                    self.v_registers[15] = if wrapped { 0 } else { 1 };
-                    second_nibble_register!() = result;
-                    self.program_counter += 2;
-                }
-                0x6 => {
-                    // Take LSB of second nibble register and store in carry/borrow, shift register right by 1
-                    let register_value = second_nibble_register!();
-                    self.v_registers[15] = register_value & 0x0001;
-                    second_nibble_register!() >>= 1;
-                    self.program_counter += 2;
-                }
-                0x7 => {
-                    // Set second nibble register to (third nibble register - second nibble register), set borrow
-                    let (result, wrapped) =
-                        third_nibble_register!().overflowing_sub(second_nibble_register!());
-                    self.v_registers[15] = if wrapped { 0 } else { 1 };
-                    second_nibble_register!() = result;
-                    self.program_counter += 2;
-                }
-                0xE => {
-                    // Take MSB of second nibble register and store in carry/borrow, shift register left by 1
-                    let register_value = second_nibble_register!();
-                    self.v_registers[15] = (register_value & 0b1000_0000) >> 7;
-                    second_nibble_register!() <<= 1;
-                    self.program_counter += 2;
-                }
-                _ => self.panic_unknown_opcode(opcode),
+            MemoryRegion {
+                name: "fontset",
+                range: fontset_start..fontset_end,
             },
-            0x9 => match fourth_nibble(opcode) {
-                0x0 => {
-                    // Skip next instruction if second nibble register does not equal third nibble register
-                    let equals: bool = second_nibble_register!() == third_nibble_register!();
-
-                    if !equals {
-                        self.program_counter += 4;
-                    } else {
-                        self.program_counter += 2;
-                    }
-                }
-                _ => self.panic_unknown_opcode(opcode),
+            MemoryRegion {
+                name: "large fontset",
+                range: fontset_end..large_fontset_end,
             },
-            0xA => {
-                // Set index register to lower three nibbles
-                self.index_register = lower_three(opcode);
-                self.program_counter += 2;
-            }
-            0xB => {
-                // Jump to lower three nibbles plus first register
-                self.program_counter =
-                    to_usize(lower_three(opcode)) + to_usize(u16::from(self.v_registers[0]));
+            MemoryRegion {
+                name: "reserved for the interpreter",
+                range: large_fontset_end..0x200,
+            },
+        ];
+
+        // Split the program area around the ROM, loads outside of it are listed on their own
+        let program = 0x200..MEMORY_SIZE;
+        match &self.loaded_range {
+            Some(rom) if rom.start >= program.start => {
+                regions.push(MemoryRegion {
+                    name: "free",
+                    range: program.start..rom.start,
+                });
+                regions.push(MemoryRegion {
+                    name: "ROM",
+                    range: rom.clone(),
+                });
+                regions.push(MemoryRegion {
+                    name: "free",
+                    range: rom.end..program.end,
+                });
             }
-            0xC => {
-                // Set second nibble register to random byte ANDed with lower half
-                second_nibble_register!() =
-                    rand::thread_rng().gen::<u8>() & to_byte(lower_half(opcode));
-                self.program_counter += 2;
+            Some(rom) => {
+                regions.push(MemoryRegion {
+                    name: "ROM",
+                    range: rom.clone(),
+                });
+                regions.push(MemoryRegion {
+                    name: "free",
+                    range: program,
+                });
             }
-            0xD => {
-                // Draw sprite with height of fourth nibble at (second nibble register, third nibble register)
-                // if any pixel gets hidden, set carry/borrow
-                let height = fourth_nibble(opcode);
-                let top_x = u16::from(second_nibble_register!());
-                let top_y = u16::from(third_nibble_register!());
+            None => regions.push(MemoryRegion {
+                name: "free",
+                range: program,
+            }),
+        }
 
-                let mut hidden: bool = false;
+        regions.retain(|region| !region.range.is_empty());
+        regions.sort_by_key(|region| region.range.start);
 
-                for y_index in 0..height {
-                    let bitmap = self.memory[usize::from(self.index_register + y_index)];
-                    for x_index in 0..8 {
-                        let y = (top_y + y_index) % SCREEN_HEIGHT;
-                        let x = (top_x + (7 - x_index)) % SCREEN_WIDTH;
-                        let framebuffer_index = usize::from(y * SCREEN_WIDTH + x);
-                        let pixel_value = (bitmap >> x_index) & 0x1;
-                        let new_value = pixel_value ^ self.periphery.framebuffer[framebuffer_index];
-
-                        if !hidden
-                            && new_value == 0
-                            && self.periphery.framebuffer[framebuffer_index] != 0
-                        {
-                            hidden = true;
-                        }
+        MemoryMap {
+            regions,
+            stack_depth: self.stack.len(),
+            stack_size: self.stack_size,
+        }
+    }
 
-                        self.periphery.framebuffer[framebuffer_index] = new_value;
-                    }
-                }
+    // Dump registers, stack, timers and memory as a JSON object for debug adapters polling
+    // between steps, see StateSnapshot for the schema
+    pub fn state_json(&self) -> String {
+        let snapshot = StateSnapshot {
+            version: 1,
+            pc: self.program_counter,
+            i: self.index_register,
+            sp: self.stack.len(),
+            stack: self.stack.clone(),
+            v: self.v_registers,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            memory: base64::encode(&self.memory[..]),
+        };
 
-                self.v_registers[15] = if hidden { 1 } else { 0 };
-                self.program_counter += 2;
-            }
-            0xE => match lower_half(opcode) {
-                0x9E => {
-                    // Skip next instruction if key at second nibble register is pressed
-                    if self.keyboard_input == second_nibble_register!() {
-                        self.program_counter += 4;
-                    } else {
-                        self.program_counter += 2;
-                    }
-                }
-                0xA1 => {
-                    // Skip next instruction if key at second nibble register is not pressed
-                    if self.keyboard_input != second_nibble_register!() {
-                        self.program_counter += 4;
-                    } else {
-                        self.program_counter += 2;
-                    }
-                }
-                _ => self.panic_unknown_opcode(opcode),
-            },
-            0xF => match lower_half(opcode) {
-                0x07 => {
-                    // Set second nibble register to delay timer's value
-                    second_nibble_register!() = self.delay_timer;
-                    self.program_counter += 2;
-                }
-                0x0A => {
-                    // Block until key-press, store result in second nibble register
-                    if self.keyboard_input != 0xff {
-                        second_nibble_register!() = self.keyboard_input;
-                        self.program_counter += 2;
-                    }
-                }
-                0x15 => {
-                    // Set delay timer to second nibble register
-                    self.delay_timer = second_nibble_register!();
-                    self.program_counter += 2;
-                }
-                0x18 => {
-                    // Set sound timer to second nibble register
-                    self.sound_timer = second_nibble_register!();
-                    if self.sound_timer > 0 {
-                        self.periphery.play_sound();
-                    }
+        serde_json::to_string(&snapshot).unwrap()
+    }
 
-                    self.program_counter += 2;
-                }
-                0x1E => {
-                    // Add second nibble register to index register
-                    self.index_register = self
-                        .index_register
-                        .wrapping_add(u16::from(second_nibble_register!()));
-                    self.program_counter += 2;
-                }
-                0x29 => {
-                    // Set index register to character sprite address determined by second nibble register
-                    self.index_register = u16::from(second_nibble_register!()) * 5 + FONTSET_OFFSET;
-                    self.program_counter += 2;
-                }
-                0x33 => {
-                    // Store BCD of second nibble register
-                    // Hundreds at index register
-                    // Tens at index register plus one
-                    // Ones at index register plus two
-
-                    // Well, let's just use a string for now :P
-                    // Yes I know there are more efficient ways but I don't want to copy.
-
-                    let mut number_string = second_nibble_register!().to_string();
-
-                    for i in 0..3 {
-                        let address = usize::from(self.index_register + i);
-                        self.memory[address] = number_string
-                            .pop()
-                            .unwrap_or('0')
-                            .to_digit(10)
-                            .unwrap()
-                            .try_into()
-                            .unwrap();
-                    }
+    // Restore registers, stack, timers and memory from JSON written by state_json. Everything
+    // is checked before anything is changed, so a corrupted state leaves the system as it was.
+    pub fn load_state_json(&mut self, json: &str) -> Result<(), LoadError> {
+        let invalid = LoadError::InvalidState;
 
-                    self.program_counter += 2;
-                }
-                0x55 => {
-                    // Store registers from first register to second nibble register (inclusive) starting at the address of the index register
-                    let upper_bound = second_nibble(opcode) + 1;
-                    for i in 0..upper_bound {
-                        let address = usize::from(self.index_register + i);
-                        self.memory[address] = self.v_registers[usize::from(i)];
-                    }
+        let snapshot: StateSnapshot =
+            serde_json::from_str(json).map_err(|e| invalid(e.to_string()))?;
 
-                    self.program_counter += 2;
-                }
-                0x65 => {
-                    // Populate registers from first register to second nibble register starting from the address stored in the index register
-                    let upper_bound = second_nibble(opcode) + 1;
-                    for i in 0..upper_bound {
-                        let address = usize::from(self.index_register + i);
-                        self.v_registers[usize::from(i)] = self.memory[address];
-                    }
+        if snapshot.version != 1 {
+            return Err(invalid(format!("unsupported version {}", snapshot.version)));
+        }
 
-                    self.program_counter += 2;
-                }
-                _ => self.panic_unknown_opcode(opcode),
-            },
-            _ => self.panic_unknown_opcode(opcode),
+        let memory =
+            base64::decode(&snapshot.memory).map_err(|e| invalid(format!("memory: {}", e)))?;
+        if memory.len() != MEMORY_SIZE {
+            return Err(invalid(format!(
+                "memory has {} bytes instead of {}",
+                memory.len(),
+                MEMORY_SIZE
+            )));
         }
+
+        if snapshot.sp != snapshot.stack.len() {
+            return Err(invalid(format!(
+                "sp {} does not match {} stack entries",
+                snapshot.sp,
+                snapshot.stack.len()
+            )));
+        }
+
+        self.check_state(snapshot.pc, &snapshot.stack)?;
+
+        self.program_counter = snapshot.pc;
+        self.index_register = snapshot.i;
+        self.stack = snapshot.stack;
+        self.v_registers = snapshot.v;
+        self.delay_timer = snapshot.delay_timer;
+        self.sound_timer = snapshot.sound_timer;
+        self.memory.copy_from_slice(&memory);
+        self.frame_count = 0;
+        self.restore_beep();
+
+        Ok(())
     }
 
-    // Write key code to input register
-    fn get_input(&mut self) {
-        self.keyboard_input = self.periphery.get_current_key_code();
+    // Snapshot the complete machine including the screen in the save state format, see
+    // SAVE_STATE_MAGIC for the layout
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut data = SAVE_STATE_MAGIC.to_vec();
+        data.push(SAVE_STATE_VERSION);
+        data.extend_from_slice(&(self.program_counter as u16).to_be_bytes());
+        data.extend_from_slice(&self.index_register.to_be_bytes());
+        data.push(self.delay_timer);
+        data.push(self.sound_timer);
+        data.extend_from_slice(&self.v_registers);
+        data.extend_from_slice(&(self.stack.len() as u16).to_be_bytes());
+        for address in self.stack.iter() {
+            data.extend_from_slice(&(*address as u16).to_be_bytes());
+        }
+        data.push(self.periphery.is_hires() as u8);
+        data.push(self.periphery.selected_planes());
+        data.push(self.pitch);
+        match self.periphery.audio_pattern() {
+            Some(pattern) => {
+                data.push(1);
+                data.extend_from_slice(&pattern);
+            }
+            None => data.push(0),
+        }
+        data.extend_from_slice(&self.rpl_flags);
+        data.extend_from_slice(&self.memory);
+        data.extend_from_slice(&self.periphery.framebuffer);
+        data
     }
 
-    // Tick frame timer
-    fn tick_frame(&mut self) {
-        let now = Instant::now();
+    // Restore a snapshot written by save_state. Like load_state_json nothing is changed unless
+    // the whole state is valid.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), LoadError> {
+        let invalid = LoadError::InvalidState;
+        let mut reader = StateReader { data };
 
-        if self.next_frame_tick <= now {
-            self.cycles_in_current_frame = 0;
-            self.periphery.draw_screen();
-            self.next_frame_tick = now.add(FRAME_INTERVAL);
+        if reader.take(SAVE_STATE_MAGIC.len())? != SAVE_STATE_MAGIC {
+            return Err(invalid(String::from("not a save state")));
+        }
+
+        let version = reader.byte()?;
+        if version != SAVE_STATE_VERSION {
+            return Err(invalid(format!("unsupported version {}", version)));
+        }
+
+        let pc = usize::from(reader.word()?);
+        let i = reader.word()?;
+        let delay_timer = reader.byte()?;
+        let sound_timer = reader.byte()?;
+        let v: [u8; 16] = reader.take(16)?.try_into().unwrap();
+
+        let depth = reader.word()?;
+        let stack = (0..depth)
+            .map(|_| reader.word().map(usize::from))
+            .collect::<Result<Vec<usize>, LoadError>>()?;
+
+        let hires = match reader.byte()? {
+            0 => false,
+            1 => true,
+            other => return Err(invalid(format!("invalid resolution flag {}", other))),
+        };
+        let planes = reader.byte()?;
+        if planes >= 1 << PLANE_COUNT {
+            return Err(invalid(format!("invalid planes {}", planes)));
         }
+        let pitch = reader.byte()?;
+        let audio_pattern: Option<[u8; AUDIO_PATTERN_SIZE]> = match reader.byte()? {
+            0 => None,
+            1 => Some(reader.take(AUDIO_PATTERN_SIZE)?.try_into().unwrap()),
+            other => return Err(invalid(format!("invalid audio pattern flag {}", other))),
+        };
+        let rpl_flags: [u8; FLAG_REGISTERS] = reader.take(FLAG_REGISTERS)?.try_into().unwrap();
+        let memory = reader.take(MEMORY_SIZE)?;
+        let size = if hires { HIRES_SIZE } else { SCREEN_SIZE };
+        let framebuffer = reader.take(size)?;
+
+        if !reader.data.is_empty() {
+            return Err(invalid(format!(
+                "{} bytes left after the state",
+                reader.data.len()
+            )));
+        }
+
+        self.check_state(pc, &stack)?;
+
+        self.program_counter = pc;
+        self.index_register = i;
+        self.stack = stack;
+        self.v_registers = v;
+        self.delay_timer = delay_timer;
+        self.sound_timer = sound_timer;
+        self.memory.copy_from_slice(memory);
+        self.periphery.set_hires(hires);
+        self.periphery.set_selected_planes(planes);
+        self.periphery.set_framebuffer(framebuffer).unwrap();
+        self.set_pitch(pitch, audio_pattern);
+        self.rpl_flags = rpl_flags;
+        self.waiting_key = None;
+        self.frame_count = 0;
+        self.restore_beep();
+
+        Ok(())
     }
 
-    // Tick both timers at 60Hz
-    fn tick_timers(&mut self) {
-        let now = Instant::now();
+    // Restore the XO-CHIP pitch register and the audio pattern, the beep plays without one
+    fn set_pitch(&mut self, pitch: u8, audio_pattern: Option<[u8; AUDIO_PATTERN_SIZE]>) {
+        self.pitch = pitch;
+        self.periphery.clear_audio_pattern();
+        self.periphery.set_beep_frequency(pitch_frequency(pitch));
 
-        if self.next_timer_tick <= now {
-            if self.delay_timer != 0 {
-                self.delay_timer -= 1;
+        if let Some(pattern) = audio_pattern {
+            self.periphery.set_audio_pattern(pattern);
+        }
+    }
+
+    // Start or stop the beep to match a restored sound timer
+    fn restore_beep(&mut self) {
+        if self.sound_timer > 0 {
+            self.beep_ticks = self.sound_timer.max(self.min_beep_ticks);
+            self.periphery.play_sound();
+        } else {
+            self.beep_ticks = 0;
+            self.periphery.stop_sound();
+        }
+    }
+
+    // Check that a restored program counter and stack describe a machine which can go on
+    fn check_state(&self, pc: usize, stack: &[usize]) -> Result<(), LoadError> {
+        let invalid = LoadError::InvalidState;
+
+        // An opcode takes two bytes, so the last byte of memory cannot start one
+        if pc >= MEMORY_SIZE - 1 {
+            return Err(invalid(format!("pc {:#X} is outside of memory", pc)));
+        }
+
+        if stack.len() > self.stack_size {
+            return Err(invalid(format!(
+                "stack holds {} return addresses, at most {} fit",
+                stack.len(),
+                self.stack_size
+            )));
+        }
+
+        if let Some(address) = stack.iter().find(|address| **address >= MEMORY_SIZE - 1) {
+            return Err(invalid(format!(
+                "return address {:#X} is outside of memory",
+                address
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Keep the flag registers in a file, reading them from it if it exists. A missing or
+    // broken file leaves the flags cleared.
+    pub fn set_flags_path(&mut self, path: Option<PathBuf>) {
+        if let Some(path) = &path {
+            match fs::read(path) {
+                Ok(flags) if flags.len() == FLAG_REGISTERS => {
+                    self.rpl_flags.copy_from_slice(&flags)
+                }
+                Ok(flags) => warn!(
+                    "Ignoring flags in {} with {} bytes instead of {}",
+                    path.display(),
+                    flags.len(),
+                    FLAG_REGISTERS
+                ),
+                Err(_) => {}
             }
+        }
+
+        self.flags_path = path;
+    }
+
+    // Save and load the state with the save state keys, nothing happens without a state file
+    pub fn set_state_path(&mut self, path: Option<PathBuf>) {
+        self.state_path = path;
+    }
+
+    // Get the framebuffer, one byte per pixel which is non-zero if the pixel is set
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.periphery.framebuffer
+    }
+
+    // Clear every plane of the framebuffer without touching the CPU state
+    pub fn clear_display(&mut self) {
+        self.periphery.clear_framebuffer();
+    }
+
+    // Get the number of frames emulated since the start, the last reset or loaded state
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // Start the program over like after power-on: registers, stack, timers, input, the screen
+    // and the frame counter are cleared. Memory and settings are kept, so a program which
+    // modified itself has to be loaded again.
+    pub fn reset(&mut self) {
+        self.program_counter = 0x200;
+        self.index_register = 0;
+        self.v_registers = [0; 16];
+        self.stack.clear();
+        self.delay_timer = 0;
+        self.sound_timer = 0;
+        self.beep_ticks = 0;
+        self.keyboard_input = 0;
+        self.pressed_keys = 0;
+        self.waiting_key = None;
+        self.input_queue.iter_mut().for_each(|keys| *keys = 0);
+        self.frame_count = 0;
+        self.cycles_in_current_frame = 0;
+        self.draws_in_current_frame = 0;
+        self.waited_for_display = false;
+        self.halted = false;
+        self.error = None;
+
+        self.periphery.stop_sound();
+        self.set_pitch(DEFAULT_PITCH, None);
+        self.periphery.set_hires(false);
+        self.periphery.set_selected_planes(1);
+        self.periphery.clear_framebuffer();
+    }
+
+    // Get register VX, panics unless X is from 0 to 15 as there is no such register
+    pub fn v_register(&self, x: usize) -> u8 {
+        self.v_registers[x]
+    }
+
+    // Get the index register I
+    pub fn index_register(&self) -> u16 {
+        self.index_register
+    }
+
+    // Get the address of the next instruction
+    pub fn program_counter(&self) -> usize {
+        self.program_counter
+    }
+
+    // Get the delay timer, counting down at 60 Hz
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    // Get the sound timer, the beep plays while it is non-zero
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    // Get a range of memory, cut off at the end of memory instead of panicking
+    pub fn memory_slice(&self, range: Range<usize>) -> &[u8] {
+        let end = range.end.min(MEMORY_SIZE);
+        &self.memory[range.start.min(end)..end]
+    }
+
+    // Get every address at which the byte pattern starts, e.g. to locate sprites or counters
+    pub fn find_in_memory(&self, pattern: &[u8]) -> Vec<usize> {
+        if pattern.is_empty() {
+            return vec![];
+        }
+
+        self.memory
+            .windows(pattern.len())
+            .enumerate()
+            .filter(|(_, window)| *window == pattern)
+            .map(|(address, _)| address)
+            .collect()
+    }
+
+    // Make every beep last at least this many frames, so very short beeps stay audible on
+    // devices which need a moment to start playing
+    pub fn set_min_beep_ticks(&mut self, ticks: u8) {
+        self.min_beep_ticks = ticks;
+    }
+
+    // Limit how many sprites can be drawn per frame, further draws stall until the next
+    // frame. A limit of 1 behaves like waiting for the display interrupt before each draw, a
+    // limit of 0 would never draw and counts as no limit.
+    pub fn set_max_draws_per_frame(&mut self, limit: Option<u32>) {
+        self.max_draws_per_frame = limit.filter(|limit| *limit > 0);
+    }
+
+    // Blank or show the display without clearing the framebuffer
+    pub fn set_display_enabled(&mut self, enabled: bool) {
+        self.periphery.set_display_enabled(enabled);
+    }
+
+    // Change the draw and background color (RGB) of the display
+    pub fn set_colors(&mut self, fg: u32, bg: u32) {
+        self.periphery.set_colors(fg, bg);
+    }
+
+    // Select the host keyboard layout used for the key map
+    pub fn set_key_layout(&mut self, key_layout: KeyLayout) {
+        self.periphery.set_key_layout(key_layout);
+    }
+
+    // Resolve opposite keys held at the same time with a filter
+    pub fn set_socd_filter(&mut self, socd_filter: SocdFilter) {
+        self.periphery.set_socd_filter(socd_filter);
+    }
+
+    // Set how many instructions are executed per 60Hz timer tick, at least one, which is how
+    // Octo defines its tickrate
+    pub fn set_cycles_per_frame(&mut self, cycles: u32) {
+        self.clock_hz = cycles.max(1).saturating_mul(TIMER_HZ);
+    }
+
+    // Set how many instructions are executed per second, clamped to 1..=10000 Hz. Takes effect
+    // with the next instruction. The clock is split into frames, so it is rounded down to a
+    // multiple of the frame rate with at least one instruction per frame, while timers keep
+    // running at 60Hz no matter the clock.
+    pub fn set_clock_speed(&mut self, hz: u32) {
+        self.clock_hz = hz.clamp(1, MAX_CLOCK_IN_HZ);
+    }
+
+    // Parse a clock speed given by the user, which has to be between 100 and 10000 Hz
+    pub fn parse_clock_speed(value: &str) -> Result<u32, String> {
+        let hz: u32 = value
+            .parse()
+            .map_err(|_| format!("Invalid clock speed: {}", value))?;
+
+        if !(MIN_USER_CLOCK_IN_HZ..=MAX_CLOCK_IN_HZ).contains(&hz) {
+            return Err(format!(
+                "Clock speed must be between {} and {} Hz: {}",
+                MIN_USER_CLOCK_IN_HZ, MAX_CLOCK_IN_HZ, hz
+            ));
+        }
+
+        Ok(hz)
+    }
+
+    // Get the number of instructions executed per second
+    pub fn clock_speed(&self) -> u32 {
+        self.clock_hz
+    }
+
+    // Set how many frames are displayed per second, 0 does not wait between frames, e.g. for
+    // benchmarking. Defaults to 60. The clock stays the same, so a lower frame rate runs more
+    // instructions per frame. Timers are not affected either, see tick_timers.
+    pub fn set_frame_rate(&mut self, fps: u32) {
+        self.fps = fps;
+    }
+
+    // Only draw every (skip + 1)th frame to save time on slow hosts, 0 draws every frame. The
+    // CPU and timers run as usual in skipped frames.
+    pub fn set_frame_skip(&mut self, skip: u32) {
+        self.frame_skip = skip;
+    }
+
+    // Count a finished frame against the frame skip, returns whether it is drawn
+    fn count_frame_skip(&mut self) -> bool {
+        if self.skipped_frames >= self.frame_skip {
+            self.skipped_frames = 0;
+            true
+        } else {
+            self.skipped_frames += 1;
+            false
+        }
+    }
+
+    // Get the wall-clock time one frame should take
+    fn frame_interval(&self) -> Duration {
+        match self.fps {
+            0 => Duration::default(),
+            fps => Duration::from_nanos(1_000_000_000 / u64::from(fps)),
+        }
+    }
+
+    // Get how many cycles run per displayed frame, uncapped frames are as long as a timer tick
+    fn cycles_per_frame(&self) -> u32 {
+        match self.fps {
+            0 => self.cycles_per_tick(),
+            fps => (self.cycles_per_second() / fps).max(1),
+        }
+    }
+
+    // Get how many cycles run per 60Hz timer tick
+    fn cycles_per_tick(&self) -> u32 {
+        (self.cycles_per_second() / TIMER_HZ).max(1)
+    }
+
+    // Get the cycle budget per second, instructions unless VIP machine cycles are counted
+    fn cycles_per_second(&self) -> u32 {
+        if self.vip_timing {
+            VIP_CYCLES_PER_TICK * TIMER_HZ
+        } else {
+            self.clock_hz
+        }
+    }
+
+    // Enable or disable refusing loads which overlap the fontset
+    pub fn set_fontset_write_protection(&mut self, enabled: bool) {
+        self.fontset_write_protection = enabled;
+    }
+
+    // Set how 0NNN opcodes are handled
+    pub fn set_machine_code_policy(&mut self, policy: MachineCodePolicy) {
+        self.machine_code_policy = policy;
+    }
+
+    // Set the maximum nesting depth of subroutine calls (at least 1), defaults to 16
+    pub fn set_stack_size(&mut self, size: usize) {
+        self.stack_size = size.max(1);
+    }
+
+    // Set what happens when a call exceeds the stack size
+    pub fn set_stack_overflow_policy(&mut self, policy: StackOverflowPolicy) {
+        self.stack_overflow_policy = policy;
+    }
+
+    // Set what happens when the run loop meets an unknown opcode, cycle always fails
+    pub fn set_unknown_opcode_policy(&mut self, policy: UnknownOpcodePolicy) {
+        self.unknown_opcode_policy = policy;
+    }
+
+    // Enable or disable panicking on any error in the run loop instead of halting
+    pub fn set_strict(&mut self, enabled: bool) {
+        self.strict = enabled;
+    }
+
+    // Set all quirks to the behavior of a platform, single quirks can be changed afterwards
+    pub fn set_platform(&mut self, platform: Platform) {
+        let vip = platform != Platform::SuperChip;
+        self.set_quirk_shift_uses_vy(vip);
+        self.set_quirk_memory_increments_index(vip);
+        self.set_quirk_jump_uses_vx(!vip);
+        self.set_quirk_display_wait(platform == Platform::Chip8);
+    }
+
+    // Enable or disable shifting VY into VX with 8XY6/8XYE as on the COSMAC VIP, by default VX
+    // is shifted in place like on SUPER-CHIP
+    pub fn set_quirk_shift_uses_vy(&mut self, enabled: bool) {
+        self.quirk_shift_uses_vy = enabled;
+    }
+
+    // Enable or disable incrementing the index register by X + 1 with FX55/FX65 as on the
+    // COSMAC VIP, by default it is left unchanged like on SUPER-CHIP
+    pub fn set_quirk_memory_increments_index(&mut self, enabled: bool) {
+        self.quirk_memory_increments_index = enabled;
+    }
+
+    // Enable or disable adding VX instead of V0 to the jump target of BXNN as on SUPER-CHIP,
+    // by default BNNN adds V0 like the COSMAC VIP
+    pub fn set_quirk_jump_uses_vx(&mut self, enabled: bool) {
+        self.quirk_jump_uses_vx = enabled;
+    }
+
+    // Enable or disable making DXYN wait for the start of the next frame before drawing like
+    // the COSMAC VIP, which limits programs to one sprite per frame
+    pub fn set_quirk_display_wait(&mut self, enabled: bool) {
+        self.quirk_display_wait = enabled;
+    }
+
+    // Enable or disable COSMAC VIP timing: each instruction uses roughly as many machine
+    // cycles as on the VIP, draws take longer the more rows they draw and wait for the next
+    // frame. The clock speed is ignored while enabled, a frame lasts as many machine cycles
+    // as on the VIP.
+    pub fn set_vip_timing(&mut self, enabled: bool) {
+        self.vip_timing = enabled;
+        self.quirk_display_wait = enabled;
+    }
+
+    // Set how many bits 8XY6/8XYE shift by, clamped to 1..=7. This is not part of any CHIP-8
+    // specification and only meant for experimenting with variants, the default of 1 is what
+    // programs expect. VF receives the last bit shifted out.
+    pub fn set_shift_amount(&mut self, amount: u8) {
+        self.shift_amount = amount.clamp(1, 7);
+    }
+
+    // Set how DXY0 draws are handled
+    pub fn set_zero_height_draw_policy(&mut self, policy: ZeroHeightDrawPolicy) {
+        self.zero_height_draw_policy = policy;
+    }
+
+    // Write the registers as CSV to the given writer at the end of every frame, starting with a
+    // header row. Writing stops with a warning on the first error.
+    pub fn set_register_log(&mut self, mut writer: Box<dyn Write>) {
+        let mut header = String::from("frame,pc");
+        for index in 0..16 {
+            header.push_str(&format!(",v{:x}", index));
+        }
+        header.push_str(",i,dt,st");
+
+        match writeln!(writer, "{}", header) {
+            Ok(()) => self.register_log = Some(writer),
+            Err(e) => warn!("Unable to write register log: {}", e),
+        }
+    }
+
+    // Set how long the blank window is shown before execution starts
+    pub fn set_boot_delay(&mut self, delay: Duration) {
+        self.boot_delay = delay;
+    }
+
+    // Enable or disable waiting for a key press before execution starts
+    pub fn set_start_paused(&mut self, enabled: bool) {
+        self.start_paused = enabled;
+    }
+
+    // Install a hook which may replace opcodes right after they are fetched, e.g. for cheats
+    pub fn set_on_fetch<F>(&mut self, on_fetch: F)
+    where
+        F: FnMut(usize, u16) -> u16 + 'static,
+    {
+        self.on_fetch = Some(Box::new(on_fetch));
+    }
+
+    // Pause the run loop and prompt on the terminal whenever the counter is non-zero, it is
+    // reset once emulation continues
+    pub fn set_interrupt_counter(&mut self, interrupts: Arc<AtomicUsize>) {
+        self.interrupts = Some(interrupts);
+    }
+
+    // Install an observer which is called after every executed instruction, e.g. to drive a
+    // live disassembly view
+    pub fn set_on_step<F>(&mut self, on_step: F)
+    where
+        F: FnMut(&StepInfo) + 'static,
+    {
+        self.on_step = Some(Box::new(on_step));
+    }
+
+    // Start or stop recording which addresses call which subroutines, stopping discards the
+    // recorded calls
+    pub fn set_call_graph_recording(&mut self, enabled: bool) {
+        self.call_graph = if enabled {
+            Some(CallGraph::default())
+        } else {
+            None
+        };
+    }
+
+    // Get the subroutine calls recorded so far
+    pub fn call_graph(&self) -> Option<&CallGraph> {
+        self.call_graph.as_ref()
+    }
+
+    // Register a condition stopping run_until_breakpoint once VX holds the value, any
+    // number of conditions can be active at once
+    pub fn break_when_register(&mut self, register: usize, value: u8) {
+        if register >= self.v_registers.len() {
+            panic!("There is no register V{:X}!", register);
+        }
+
+        self.register_conditions
+            .push(RegisterCondition { register, value });
+    }
+
+    // Log the address of every 00E0 and briefly flash the screen content before clearing
+    // it, to track down unexpected clears
+    pub fn set_debug_clears(&mut self, enabled: bool) {
+        self.debug_clears = enabled;
+    }
+
+    // Name an address, traces then show the name for jumps and calls to it
+    pub fn add_label(&mut self, address: usize, name: &str) {
+        self.labels.insert(address, name);
+    }
+
+    // Replace all address names used when tracing
+    pub fn set_labels(&mut self, labels: Labels) {
+        self.labels = labels;
+    }
+
+    // Enable or disable test mode, reserving 0F00 (pass and halt) and 0FNN with NN != 0
+    // (assertion NN failed) for self-testing ROMs
+    pub fn set_test_mode(&mut self, enabled: bool) {
+        self.test_mode = enabled;
+    }
 
-            if self.sound_timer != 0 {
-                self.sound_timer -= 1;
+    // Get the error which halted the system, if any
+    pub fn error(&self) -> Option<EmulationError> {
+        self.error
+    }
+
+    // Get the assertions signalled so far in test mode
+    pub fn test_results(&self) -> &TestResults {
+        &self.test_results
+    }
+
+    // Restrict execution to a range of addresses, the default range covers the program area
+    pub fn set_executable_range(&mut self, range: Option<Range<usize>>) {
+        self.executable_range = range;
+    }
+
+    // Warn whenever an instruction is executed from an odd offset relative to 0x200, which
+    // usually means a bad jump
+    pub fn set_alignment_check(&mut self, enabled: bool) {
+        self.check_alignment = enabled;
+    }
+
+    // Get the program area, the default executable range. The last byte is excluded since no
+    // whole opcode fits there.
+    pub fn program_range() -> Range<usize> {
+        0x200..MEMORY_SIZE - 1
+    }
+
+    // Delay input by a number of frames: a key state sampled at the end of frame k is seen by
+    // Ex9E/ExA1/Fx0A during frame k + 1 + frames. No key is pressed during the first frames.
+    pub fn set_input_delay(&mut self, frames: usize) {
+        self.input_queue = VecDeque::from(vec![0; frames]);
+    }
+
+    // Write every distinct opcode to the given writer the first time it is executed. Writing
+    // stops with a warning on the first error.
+    pub fn set_trace_unique(&mut self, writer: Box<dyn Write>) {
+        self.trace_unique = Some(writer);
+    }
+
+    // Load data, fails without touching memory if it does not fit or would overwrite the
+    // write protected fontset
+    pub fn copy_buffer_to_memory(
+        &mut self,
+        buffer: Vec<u8>,
+        offset: usize,
+    ) -> Result<(), LoadError> {
+        let available = MEMORY_SIZE.saturating_sub(offset);
+        if buffer.len() > available {
+            return Err(LoadError::TooLarge {
+                size: buffer.len(),
+                available,
+            });
+        }
+
+        if overlaps_fontset(offset, buffer.len()) {
+            if self.fontset_write_protection {
+                return Err(LoadError::FontsetOverlap { offset });
             } else {
-                self.periphery.stop_sound();
+                warn!(
+                    "Data loaded at {:#X} overwrites the fontset, Fx29 glyphs will be broken.",
+                    offset
+                );
             }
+        }
 
-            self.next_timer_tick = now.add(TIMER_INTERVAL);
+        self.memory[offset..offset + buffer.len()].copy_from_slice(&buffer);
+        self.loaded_range = Some(offset..offset + buffer.len());
+        info!("Loaded {} bytes at {:#X}", buffer.len(), offset);
+
+        Ok(())
+    }
+
+    // Enter main run loop (blocks until the window is closed)
+    pub fn run(&mut self) {
+        self.boot();
+
+        while !self.periphery.is_window_closed() {
+            if self.is_interrupted() && !self.prompt_while_paused() {
+                break;
+            }
+
+            // Limit maximum number of cycles per frame, a halted system only keeps the window alive.
+            // Fast-forwarded frames last one timer tick, see fast_forward_frame.
+            let frame_cycles = if self.turbo {
+                self.cycles_per_tick()
+            } else {
+                self.cycles_per_frame()
+            };
+
+            if !self.halted && self.cycles_in_current_frame < frame_cycles {
+                self.cycle_or_halt();
+                self.cycles_in_current_frame += self.cycle_cost;
+            } else {
+                self.finish_frame();
+            }
         }
+
+        // Do not leave a tone hanging around until the process exits
+        self.periphery.stop_sound();
     }
 
-    // Sleep if needed (we assume a 1ms accuracy of the sleep timer)
-    fn sleep_if_needed(&mut self) {
+    // Show the screen before the first instruction runs, for the boot delay and then until a
+    // key is pressed if starting paused. Frame pacing starts over afterwards so the run loop
+    // does not try to catch up on the time spent here.
+    fn boot(&mut self) {
+        let boot_end = Instant::now().add(self.boot_delay);
+
+        while Instant::now() < boot_end && !self.periphery.is_window_closed() {
+            self.periphery.draw_screen();
+            sleep(
+                self.frame_interval()
+                    .min(boot_end.saturating_duration_since(Instant::now())),
+            );
+        }
+
+        if self.start_paused {
+            info!("Press any key to start");
+            self.periphery.wait_for_key_press();
+        }
+
         let now = Instant::now();
+        self.next_frame_tick = now;
+        self.next_timer_tick = now;
+    }
 
-        if now < self.next_frame_tick {
-            let until = self.next_frame_tick.sub(now);
+    // Check whether an interrupt was requested since the run loop last continued
+    fn is_interrupted(&self) -> bool {
+        self.interrupt_count() > 0
+    }
 
-            if until > Duration::from_millis(1) {
-                sleep(until.sub(Duration::from_millis(1)));
+    fn interrupt_count(&self) -> usize {
+        match &self.interrupts {
+            Some(interrupts) => interrupts.load(Ordering::SeqCst),
+            None => 0,
+        }
+    }
+
+    // Read an answer to the pause prompt, None if stdin is closed or another interrupt asks to
+    // quit. Stdin is read on its own thread since an interrupt does not end a blocking read.
+    fn read_prompt_answer(&self) -> Option<String> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut answer = String::new();
+            let read = io::stdin().read_line(&mut answer);
+            let _ = sender.send(match read {
+                Ok(0) | Err(_) => None,
+                Ok(_) => Some(answer),
+            });
+        });
+
+        loop {
+            if self.interrupt_count() > 1 {
+                return None;
+            }
+
+            match receiver.recv_timeout(PROMPT_POLL_INTERVAL) {
+                Ok(answer) => return answer,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return None,
             }
         }
     }
 
-    fn panic_unknown_opcode(&self, opcode: u16) {
-        panic!(
-            "Unknown opcode: {:#X} at address {:#X}!",
-            opcode, self.program_counter
-        );
+    // Ask on the terminal how to go on after an interrupt, returns false to quit. The window
+    // is not updated while waiting for an answer.
+    fn prompt_while_paused(&mut self) -> bool {
+        self.periphery.stop_sound();
+
+        loop {
+            let address = self.program_counter;
+            let opcode = match self.memory.get(address..address + 2) {
+                Some(&[upper, lower]) => u16::from(upper) << 8 | u16::from(lower),
+                _ => 0,
+            };
+
+            print!(
+                "Paused at 0x{:03X}: {:04X}  {}  [c]ontinue, [s]tep, run until [d]raw, [f]ind BYTES or [q]uit? ",
+                address,
+                opcode,
+                disassemble_opcode_labeled(opcode, &self.labels)
+            );
+            io::stdout().flush().unwrap();
+
+            let answer = match self.read_prompt_answer() {
+                Some(answer) => answer,
+                None => return false,
+            };
+
+            // Find takes the bytes to search for after the command
+            let answer = answer.trim();
+            let (command, bytes) = answer.split_at(answer.find(' ').unwrap_or(answer.len()));
+            if command == "f" || command == "find" {
+                match parse_byte_pattern(bytes) {
+                    Ok(pattern) => self.print_memory_matches(&pattern),
+                    Err(e) => println!("{}", e),
+                }
+                continue;
+            }
+
+            match answer {
+                "c" | "continue" | "" => {
+                    if let Some(interrupts) = &self.interrupts {
+                        interrupts.store(0, Ordering::SeqCst);
+                    }
+                    return true;
+                }
+                "s" | "step" | "d" | "draw" if self.halted => println!("The system is halted."),
+                "s" | "step" => self.cycle_or_halt(),
+                "d" | "draw" => {
+                    if !self.run_until_draw(PROMPT_DRAW_CYCLES) {
+                        println!("No sprite drawn in {} cycles.", PROMPT_DRAW_CYCLES);
+                    }
+                    self.periphery.draw_screen();
+                }
+                "q" | "quit" => return false,
+                other => println!("Unknown command: {}", other),
+            }
+        }
+    }
+
+    // List every address the byte pattern starts at for the pause prompt
+    fn print_memory_matches(&self, pattern: &[u8]) {
+        let addresses: Vec<String> = self
+            .find_in_memory(pattern)
+            .iter()
+            .map(|address| format!("0x{:03X}", address))
+            .collect();
+
+        if addresses.is_empty() {
+            println!("Not found in memory.");
+        } else {
+            println!("Found at {}", addresses.join(", "));
+        }
+    }
+
+    // Execute a fixed number of cycles without waiting for wall-clock time, input and timers
+    // are updated once per emulated frame. Stops early if the system halts.
+    pub fn run_cycles(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            if self.halted {
+                break;
+            }
+
+            self.virtual_cycle();
+        }
+    }
+
+    // Like run_cycles, but stops after the first instruction which satisfies a register
+    // condition and returns that condition
+    pub fn run_until_breakpoint(&mut self, cycles: u64) -> Option<RegisterCondition> {
+        for _ in 0..cycles {
+            if self.halted {
+                break;
+            }
+
+            self.virtual_cycle();
+
+            let v_registers = &self.v_registers;
+            let fired = self
+                .register_conditions
+                .iter()
+                .find(|condition| v_registers[condition.register] == condition.value);
+
+            if let Some(condition) = fired {
+                info!(
+                    "Breakpoint at address {:#X}: {}",
+                    self.program_counter, condition
+                );
+                return Some(*condition);
+            }
+        }
+
+        None
+    }
+
+    // Like run_cycles, but stops right after the next sprite was drawn, returns whether a
+    // sprite was drawn. Watching the framebuffer after each call shows a scene being composed.
+    pub fn run_until_draw(&mut self, cycles: u64) -> bool {
+        let draw_count = self.draw_count;
+
+        for _ in 0..cycles {
+            if self.halted {
+                break;
+            }
+
+            self.virtual_cycle();
+
+            if self.draw_count != draw_count {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    // Execute exactly one instruction in virtual time for debuggers and test harnesses, stepping
+    // through a ROM. Unlike cycle, timers and input advance like in run_cycles. A failed
+    // instruction is returned without advancing the program counter or halting the system.
+    pub fn step(&mut self) -> Result<(), EmulationError> {
+        self.cycle()?;
+        self.advance_virtual_frame();
+        Ok(())
+    }
+
+    // Execute a single cycle in virtual time, advancing the frame once enough cycles ran. Virtual
+    // frames last one timer tick no matter the frame rate.
+    fn virtual_cycle(&mut self) {
+        self.cycle_or_halt();
+        self.advance_virtual_frame();
+    }
+
+    // Account for the cost of the last cycle, finishing the virtual frame once it is full
+    fn advance_virtual_frame(&mut self) {
+        self.cycles_in_current_frame += self.cycle_cost;
+
+        if self.cycles_in_current_frame >= self.cycles_per_tick() {
+            self.log_registers();
+            self.cycles_in_current_frame = 0;
+            self.draws_in_current_frame = 0;
+            self.frame_count += 1;
+            self.get_input();
+            self.decrement_timers();
+        }
+    }
+
+    // Execute a cycle, handling a failed instruction as configured: unknown opcodes may be
+    // skipped, otherwise the system panics in strict mode or halts
+    fn cycle_or_halt(&mut self) {
+        match self.cycle() {
+            Ok(()) => {}
+            Err(e) if self.strict => panic!("{}", e),
+            Err(e @ EmulationError::UnknownOpcode { .. })
+                if self.unknown_opcode_policy == UnknownOpcodePolicy::Skip =>
+            {
+                warn!("{} Skipping it.", e);
+                self.program_counter += 2;
+            }
+            Err(e) => {
+                error!("{}", e);
+                self.error = Some(e);
+                self.halted = true;
+            }
+        }
+    }
+
+    // Execute a single instruction right away, without frame pacing, timers or input. An
+    // instruction which cannot be executed fails without advancing the program counter.
+    pub fn cycle(&mut self) -> Result<(), EmulationError> {
+        // Catch runaway program counters before fetching
+        if let Some(range) = &self.executable_range {
+            if !range.contains(&self.program_counter) {
+                return Err(EmulationError::OutsideExecutableRegion {
+                    pc: self.program_counter,
+                    start: range.start,
+                    end: range.end,
+                });
+            }
+        }
+
+        if self.check_alignment
+            && self.program_counter.wrapping_sub(0x200) & 1 == 1
+            && self.misaligned_addresses.insert(self.program_counter)
+        {
+            warn!(
+                "Executing misaligned instruction at address {:#X}",
+                self.program_counter
+            );
+        }
+
+        // Get current op code, a program counter at the last byte or beyond (after a jump or by
+        // running off the end) cannot fetch one, so there is no opcode to report either
+        let pc = self.program_counter;
+        let mut opcode: u16 = match self.memory.get(pc..pc.saturating_add(2)) {
+            Some(&[upper, lower]) => u16::from(upper) << 8 | u16::from(lower),
+            _ => {
+                return Err(EmulationError::MemoryOutOfBounds {
+                    opcode: 0,
+                    pc,
+                    start: pc,
+                    end: pc.saturating_add(2),
+                })
+            }
+        };
+
+        if let Some(on_fetch) = self.on_fetch.as_mut() {
+            opcode = on_fetch(self.program_counter, opcode);
+        }
+
+        self.cycle_cost = 1;
+
+        trace!(
+            "0x{:03X}: {:04X}  {}",
+            self.program_counter,
+            opcode,
+            disassemble_opcode_labeled(opcode, &self.labels)
+        );
+
+        if let Some(writer) = self.trace_unique.as_mut() {
+            if self.seen_opcodes.insert(opcode) {
+                let written = writeln!(
+                    writer,
+                    "0x{:03X}: {:04X}  {}",
+                    self.program_counter,
+                    opcode,
+                    disassemble_opcode_labeled(opcode, &self.labels)
+                );
+
+                if let Err(e) = written {
+                    warn!("Unable to write opcode trace: {}", e);
+                    self.trace_unique = None;
+                }
+            }
+        }
+
+        self.execute(decode(opcode), opcode)
+    }
+
+    // Execute a decoded instruction, the opcode it was decoded from is used for error reports.
+    // Instructions which set a flag write VF last so the flag wins whenever VF is also used as
+    // an operand or destination.
+    #[allow(clippy::cognitive_complexity)]
+    fn execute(&mut self, instruction: Instruction, opcode: u16) -> Result<(), EmulationError> {
+        let address = self.program_counter;
+
+        match instruction {
+            Instruction::ClearScreen => {
+                if self.debug_clears {
+                    warn!("Screen cleared at address {:#X}", self.program_counter);
+                    self.periphery.flash_screen();
+                }
+
+                self.periphery.clear_selected_planes();
+                self.program_counter += 2;
+            }
+            Instruction::Return => match self.stack.pop() {
+                Some(address) => self.program_counter = address,
+                None => {
+                    return Err(EmulationError::StackUnderflow {
+                        pc: self.program_counter,
+                    })
+                }
+            },
+            Instruction::ScrollDown(rows) => {
+                // Scroll the display down by a number of pixel rows (SUPER-CHIP)
+                self.periphery.scroll_down(usize::from(rows));
+                self.program_counter += 2;
+            }
+            Instruction::LowResolution => {
+                self.periphery.set_hires(false);
+                self.program_counter += 2;
+            }
+            Instruction::HighResolution => {
+                self.periphery.set_hires(true);
+                self.program_counter += 2;
+            }
+            Instruction::ScrollRight => {
+                self.periphery.scroll_right(HORIZONTAL_SCROLL_COLUMNS);
+                self.program_counter += 2;
+            }
+            Instruction::ScrollLeft => {
+                self.periphery.scroll_left(HORIZONTAL_SCROLL_COLUMNS);
+                self.program_counter += 2;
+            }
+            Instruction::MachineCode(target) if self.test_mode && target & 0xF00 == 0xF00 => {
+                // Test assertion, 0F00 passes and halts, anything else records a failure
+                let code = to_byte(lower_half(target));
+
+                if code == 0 {
+                    info!("Test passed at address {:#X}", self.program_counter);
+                    self.test_results.passed = true;
+                    self.halted = true;
+                } else {
+                    warn!(
+                        "Assertion {} failed at address {:#X}",
+                        code, self.program_counter
+                    );
+                    self.test_results
+                        .failures
+                        .push((self.program_counter, code));
+                    self.program_counter += 2;
+                }
+            }
+            Instruction::MachineCode(_) => match self.machine_code_policy {
+                MachineCodePolicy::Strict => return Err(self.unknown_opcode(opcode)),
+                MachineCodePolicy::Ignore => {
+                    // Call program at target, ignored
+                    self.program_counter += 2;
+                }
+                MachineCodePolicy::Halt => {
+                    warn!(
+                        "Halting on machine code call {:#X} at address {:#X}",
+                        opcode, self.program_counter
+                    );
+                    self.halted = true;
+                }
+            },
+            Instruction::Jump(target) => self.program_counter = usize::from(target),
+            Instruction::Call(target) => {
+                if let Some(call_graph) = self.call_graph.as_mut() {
+                    call_graph
+                        .calls
+                        .insert((self.program_counter, usize::from(target)));
+                }
+
+                if self.stack.len() >= self.stack_size {
+                    match self.stack_overflow_policy {
+                        StackOverflowPolicy::Error => {
+                            return Err(EmulationError::StackOverflow {
+                                pc: self.program_counter,
+                                stack_size: self.stack_size,
+                            })
+                        }
+                        StackOverflowPolicy::Wrap => {
+                            self.stack.remove(0);
+                        }
+                        StackOverflowPolicy::Ignore => {
+                            warn!(
+                                "Skipping call at address {:#X}, stack is full",
+                                self.program_counter
+                            );
+                            self.program_counter += 2;
+                            return Ok(());
+                        }
+                    }
+                }
+
+                self.stack.push(self.program_counter + 2);
+                self.program_counter = usize::from(target);
+            }
+            Instruction::SkipIfEqual(x, byte) => self.skip_if(self.v(x) == byte),
+            Instruction::SkipIfNotEqual(x, byte) => self.skip_if(self.v(x) != byte),
+            Instruction::SkipIfRegistersEqual(x, y) => self.skip_if(self.v(x) == self.v(y)),
+            Instruction::SetRegister(x, byte) => {
+                self.set_v(x, byte);
+                self.program_counter += 2;
+            }
+            Instruction::AddToRegister(x, byte) => {
+                // Does not affect the carry flag
+                self.set_v(x, self.v(x).wrapping_add(byte));
+                self.program_counter += 2;
+            }
+            Instruction::CopyRegister(x, y) => {
+                self.set_v(x, self.v(y));
+                self.program_counter += 2;
+            }
+            Instruction::Or(x, y) => {
+                self.set_v(x, self.v(x) | self.v(y));
+                self.program_counter += 2;
+            }
+            Instruction::And(x, y) => {
+                self.set_v(x, self.v(x) & self.v(y));
+                self.program_counter += 2;
+            }
+            Instruction::Xor(x, y) => {
+                self.set_v(x, self.v(x) ^ self.v(y));
+                self.program_counter += 2;
+            }
+            Instruction::Add(x, y) => {
+                // Set carry
+                let (result, wrapped) = self.v(x).overflowing_add(self.v(y));
+                self.set_v(x, result);
+                self.v_registers[15] = if wrapped { 1 } else { 0 };
+                self.program_counter += 2;
+            }
+            Instruction::Subtract(x, y) => {
+                // Set borrow
+                let (result, wrapped) = self.v(x).overflowing_sub(self.v(y));
+                self.set_v(x, result);
+                self.v_registers[15] = if wrapped { 0 } else { 1 };
+                self.program_counter += 2;
+            }
+            Instruction::ShiftRight(x, y) => {
+                // Store the last bit shifted out in carry/borrow. With the shift quirk VY is
+                // shifted into VX.
+                let register_value = if self.quirk_shift_uses_vy {
+                    self.v(y)
+                } else {
+                    self.v(x)
+                };
+                self.set_v(x, register_value >> self.shift_amount);
+                self.v_registers[15] = (register_value >> (self.shift_amount - 1)) & 0x0001;
+                self.program_counter += 2;
+            }
+            Instruction::SubtractFrom(x, y) => {
+                // Set VX to VY - VX, set borrow
+                let (result, wrapped) = self.v(y).overflowing_sub(self.v(x));
+                self.set_v(x, result);
+                self.v_registers[15] = if wrapped { 0 } else { 1 };
+                self.program_counter += 2;
+            }
+            Instruction::ShiftLeft(x, y) => {
+                // Store the last bit shifted out in carry/borrow. With the shift quirk VY is
+                // shifted into VX.
+                let register_value = if self.quirk_shift_uses_vy {
+                    self.v(y)
+                } else {
+                    self.v(x)
+                };
+                self.set_v(x, register_value << self.shift_amount);
+                self.v_registers[15] =
+                    ((register_value << (self.shift_amount - 1)) & 0b1000_0000) >> 7;
+                self.program_counter += 2;
+            }
+            Instruction::SkipIfRegistersNotEqual(x, y) => self.skip_if(self.v(x) != self.v(y)),
+            Instruction::SetIndex(value) => {
+                self.index_register = value;
+                self.program_counter += 2;
+            }
+            Instruction::JumpWithOffset(target) => {
+                // The offset register is the highest nibble of the target with the jump quirk
+                let offset_register = if self.quirk_jump_uses_vx {
+                    to_byte(second_nibble(opcode))
+                } else {
+                    0
+                };
+                self.program_counter = usize::from(target) + usize::from(self.v(offset_register));
+            }
+            Instruction::Random(x, byte) => {
+                self.set_v(x, rand::thread_rng().gen::<u8>() & byte);
+                self.program_counter += 2;
+            }
+            Instruction::DrawSprite { x, y, height } => {
+                // DXY0 draws 16x16 in 128x64 mode like SUPER-CHIP, otherwise it depends on the
+                // policy
+                let (height, width) = match (u16::from(height), self.zero_height_draw_policy) {
+                    (0, _) if self.periphery.is_hires() => (16, 16),
+                    (0, ZeroHeightDrawPolicy::Error) => {
+                        return Err(EmulationError::ZeroHeightDraw {
+                            opcode,
+                            pc: self.program_counter,
+                        })
+                    }
+                    (0, ZeroHeightDrawPolicy::Large) => (16, 16),
+                    (height, _) => (height, 8),
+                };
+                let bytes_per_row = usize::from(width / 8);
+
+                // Once the draw limit is reached the draw is retried until the next frame starts
+                if let Some(limit) = self.max_draws_per_frame {
+                    if self.draws_in_current_frame >= limit {
+                        return Ok(());
+                    }
+                }
+
+                // Waiting for the display uses up the rest of the frame, the draw is retried
+                // at the start of the next one
+                if self.quirk_display_wait && !self.waited_for_display {
+                    self.waited_for_display = true;
+                    self.cycle_cost = self
+                        .cycles_per_frame()
+                        .saturating_sub(self.cycles_in_current_frame)
+                        .max(1);
+                    return Ok(());
+                }
+                self.waited_for_display = false;
+                self.draw_count += 1;
+                self.draws_in_current_frame += 1;
+
+                // Draw sprite at (VX, VY), if any pixel gets hidden, set carry/borrow
+                // Rows which would be read from beyond the end of memory are not drawn, the
+                // draw stops early and carry/borrow only reflects the rows drawn so far
+                // The height always comes from the opcode, a small font glyph from Fx29 is
+                // drawn with height 5 no matter which font opcode set the index register
+                // The starting position wraps around the active resolution before drawing
+                // Every selected XO-CHIP plane gets its own sprite, stored one after another
+                // at I starting with the lowest plane
+                let (screen_width, screen_height) = self.resolution();
+                let top_x = u16::from(self.v(x)) % screen_width;
+                let top_y = u16::from(self.v(y)) % screen_height;
+                let sprite_size = usize::from(height) * bytes_per_row;
+                let selected_planes = self.periphery.selected_planes();
+                let planes = (0..PLANE_COUNT).filter(|plane| selected_planes & 1 << plane != 0);
+
+                let mut hidden: bool = false;
+
+                for (sprite_index, plane) in planes.enumerate() {
+                    let sprite_start =
+                        usize::from(self.index_register) + sprite_index * sprite_size;
+                    let plane_bit = 1 << plane;
+
+                    for y_index in 0..height {
+                        let address = sprite_start + usize::from(y_index) * bytes_per_row;
+                        if address + bytes_per_row > MEMORY_SIZE {
+                            break;
+                        }
+
+                        // Rows are read straight from memory on every draw. Caching unpacked
+                        // rows by I and height made draw-heavy code slower, as the pixel loop
+                        // dominates, and would need invalidating on every memory write.
+                        let bitmap = self.memory[address..address + bytes_per_row]
+                            .iter()
+                            .fold(0u16, |bitmap, &byte| (bitmap << 8) | u16::from(byte));
+                        for x_index in 0..width {
+                            if (bitmap >> x_index) & 0x1 == 0 {
+                                continue;
+                            }
+
+                            let y = (top_y + y_index) % screen_height;
+                            let x = (top_x + (width - 1 - x_index)) % screen_width;
+                            let pixel =
+                                &mut self.periphery.framebuffer[usize::from(y * screen_width + x)];
+
+                            if *pixel & plane_bit != 0 {
+                                hidden = true;
+                            }
+
+                            *pixel ^= plane_bit;
+                        }
+                    }
+                }
+
+                self.v_registers[15] = if hidden { 1 } else { 0 };
+                self.program_counter += 2;
+
+                // Every byte of the sprite is shifted into place and XORed onto the screen
+                if self.vip_timing {
+                    self.cycle_cost = VIP_DRAW_CYCLES
+                        + u32::from(height) * bytes_per_row as u32 * VIP_DRAW_BYTE_CYCLES;
+                }
+            }
+            Instruction::SkipIfKey(x) => self.skip_if(self.is_key_down(self.v(x))),
+            Instruction::SkipIfNotKey(x) => self.skip_if(!self.is_key_down(self.v(x))),
+            Instruction::LongIndex => {
+                // Load a full 16 bit address from the word following the opcode
+                let start = self.program_counter + 2;
+                if start + 2 > MEMORY_SIZE {
+                    return Err(EmulationError::MemoryOutOfBounds {
+                        opcode,
+                        pc: self.program_counter,
+                        start,
+                        end: start + 2,
+                    });
+                }
+
+                self.index_register =
+                    u16::from(self.memory[start]) << 8 | u16::from(self.memory[start + 1]);
+                self.program_counter += 4;
+            }
+            Instruction::SelectPlanes(planes) => {
+                self.periphery.set_selected_planes(planes);
+                self.program_counter += 2;
+            }
+            Instruction::LoadAudioPattern => {
+                // The pattern replaces the beep until the next one is loaded
+                let range = self.index_range(opcode, AUDIO_PATTERN_SIZE)?;
+                let mut pattern = [0; AUDIO_PATTERN_SIZE];
+                pattern.copy_from_slice(&self.memory[range]);
+                self.periphery.set_audio_pattern(pattern);
+                self.program_counter += 2;
+            }
+            Instruction::GetDelayTimer(x) => {
+                self.set_v(x, self.delay_timer);
+                self.program_counter += 2;
+            }
+            Instruction::WaitForKey(x) => {
+                // Block until a key is pressed and released again like the COSMAC VIP, store
+                // the key in VX. Of keys pressed together the lowest one is picked, other keys
+                // held by then have to be released and pressed again.
+                match self.waiting_key {
+                    Some(key) if !self.is_key_down(key) => {
+                        self.set_v(x, key);
+                        self.waiting_key = None;
+                        self.program_counter += 2;
+                    }
+                    Some(_) => {}
+                    None if self.pressed_keys != 0 => {
+                        self.waiting_key = Some(self.pressed_keys.trailing_zeros() as u8);
+                        self.pressed_keys = 0;
+                    }
+                    None => {}
+                }
+            }
+            Instruction::SetDelayTimer(x) => {
+                self.delay_timer = self.v(x);
+                self.timer_stats.delay_timer_sets += 1;
+                self.timer_stats.delay_timer_max =
+                    self.timer_stats.delay_timer_max.max(self.delay_timer);
+                self.program_counter += 2;
+            }
+            Instruction::SetSoundTimer(x) => {
+                self.sound_timer = self.v(x);
+                self.timer_stats.sound_timer_sets += 1;
+                self.timer_stats.sound_timer_max =
+                    self.timer_stats.sound_timer_max.max(self.sound_timer);
+
+                if self.sound_timer > 0 {
+                    self.beep_ticks = self.sound_timer.max(self.min_beep_ticks);
+                    self.periphery.play_sound();
+                } else {
+                    self.beep_ticks = 0;
+                }
+
+                self.program_counter += 2;
+            }
+            Instruction::AddToIndex(x) => {
+                self.index_register = self.index_register.wrapping_add(u16::from(self.v(x)));
+                self.program_counter += 2;
+            }
+            Instruction::SetFont(x) => {
+                // Point the index register at the character sprite of VX
+                self.index_register = u16::from(self.v(x)) * 5 + FONTSET_OFFSET;
+                self.program_counter += 2;
+            }
+            Instruction::SetLargeFont(x) => {
+                // Point the index register at the large digit of VX, only 0 to 9 exist
+                let digit = self.v(x);
+                if digit > 9 {
+                    return Err(EmulationError::InvalidLargeDigit {
+                        opcode,
+                        pc: address,
+                        digit,
+                    });
+                }
+
+                self.index_register = u16::from(digit) * 10 + LARGE_FONTSET_OFFSET;
+                self.program_counter += 2;
+            }
+            Instruction::StoreBcd(x) => {
+                // Store BCD of VX
+                // Hundreds at index register
+                // Tens at index register plus one
+                // Ones at index register plus two
+                let value = self.v(x);
+                let range = self.index_range(opcode, 3)?;
+                self.memory[range].copy_from_slice(&[value / 100, value / 10 % 10, value % 10]);
+
+                self.program_counter += 2;
+            }
+            Instruction::SetPitch(x) => {
+                self.pitch = self.v(x);
+                self.periphery
+                    .set_beep_frequency(pitch_frequency(self.pitch));
+                self.program_counter += 2;
+            }
+            Instruction::StoreRegisters(_) => {
+                // Store registers from V0 to VX (inclusive) starting at the address of the index register
+                // Touches memory I..=I+X, the index register is only changed by the memory quirk
+                let range = self.register_file_range(opcode)?;
+                let count = range.len();
+                self.memory[range].copy_from_slice(&self.v_registers[..count]);
+                self.advance_index_after_memory_access(count);
+
+                self.program_counter += 2;
+            }
+            Instruction::LoadRegisters(_) => {
+                // Populate registers from V0 to VX starting from the address stored in the index register
+                // Touches memory I..=I+X, the index register is only changed by the memory quirk
+                let range = self.register_file_range(opcode)?;
+                let count = range.len();
+                self.v_registers[..count].copy_from_slice(&self.memory[range]);
+                self.advance_index_after_memory_access(count);
+
+                self.program_counter += 2;
+            }
+            Instruction::StoreFlags(x) => {
+                // Store V0 to VX (inclusive) in the flag registers and write them to disk
+                let count = self.flag_count(x, opcode)?;
+                self.rpl_flags[..count].copy_from_slice(&self.v_registers[..count]);
+                self.write_flags();
+
+                self.program_counter += 2;
+            }
+            Instruction::LoadFlags(x) => {
+                // Populate V0 to VX (inclusive) from the flag registers
+                let count = self.flag_count(x, opcode)?;
+                self.v_registers[..count].copy_from_slice(&self.rpl_flags[..count]);
+
+                self.program_counter += 2;
+            }
+            Instruction::Unknown(_) => return Err(self.unknown_opcode(opcode)),
+        }
+
+        if self.vip_timing && first_nibble(opcode) != 0xD {
+            self.cycle_cost = vip_cycles(opcode, self.program_counter > address + 2);
+        }
+
+        if let Some(on_step) = self.on_step.as_mut() {
+            on_step(&StepInfo {
+                address,
+                opcode,
+                next_address: self.program_counter,
+                frame: self.frame_count,
+            });
+        }
+
+        Ok(())
+    }
+
+    // Get how many registers FX75/FX85 copy, there are no flag registers beyond the eighth
+    fn flag_count(&self, x: u8, opcode: u16) -> Result<usize, EmulationError> {
+        let count = usize::from(x) + 1;
+
+        if count > FLAG_REGISTERS {
+            return Err(self.unknown_opcode(opcode));
+        }
+
+        Ok(count)
+    }
+
+    // Write the flag registers to the flags file, failures are only logged
+    fn write_flags(&self) {
+        if let Some(path) = &self.flags_path {
+            if let Err(e) = fs::write(path, self.rpl_flags) {
+                error!("Unable to save flags to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    // Move the index register past the registers FX55/FX65 accessed if the memory quirk is on
+    fn advance_index_after_memory_access(&mut self, count: usize) {
+        if self.quirk_memory_increments_index {
+            self.index_register += count as u16;
+        }
+    }
+
+    // Get a V register
+    fn v(&self, register: u8) -> u8 {
+        self.v_registers[usize::from(register)]
+    }
+
+    // Set a V register
+    fn set_v(&mut self, register: u8, value: u8) {
+        self.v_registers[usize::from(register)] = value;
+    }
+
+    // Skip the next instruction if the condition holds, otherwise continue with it
+    fn skip_if(&mut self, condition: bool) {
+        if !condition {
+            self.program_counter += 2;
+            return;
+        }
+
+        // F000 NNNN is skipped as a whole like on XO-CHIP
+        let next = self.program_counter + 2;
+        let skipped_long = self.memory.get(next..next + 2) == Some(&[0xF0, 0x00][..]);
+        self.program_counter += if skipped_long { 6 } else { 4 };
+    }
+
+    // Wait for the end of the frame in the run loop, which comes by here many times while the
+    // frame is not due yet. Keys are only sampled once the frame advanced, otherwise the passes
+    // in between would drain the input delay queue.
+    fn finish_frame(&mut self) {
+        self.poll_hotkeys();
+
+        let advanced = if self.turbo {
+            self.fast_forward_frame();
+            true
+        } else {
+            let advanced = self.tick_frame();
+            self.tick_timers();
+            self.sleep_if_needed();
+            advanced
+        };
+
+        if advanced {
+            let keys = self.periphery.get_current_keys();
+            self.push_input(keys);
+        }
+    }
+
+    // Write held keys to input register and check for fast-forward
+    fn get_input(&mut self) {
+        let keys = self.periphery.get_current_keys();
+        self.push_input(keys);
+        self.poll_hotkeys();
+    }
+
+    // Check for fast-forward and the save state keys
+    fn poll_hotkeys(&mut self) {
+        self.turbo = self.periphery.is_turbo_key_down();
+
+        if self.periphery.is_save_state_key_pressed() {
+            self.save_state_file();
+        } else if self.periphery.is_load_state_key_pressed() {
+            self.load_state_file();
+        }
+    }
+
+    // Write the save state to the state file, failures are only logged
+    fn save_state_file(&self) {
+        let path = match &self.state_path {
+            Some(path) => path,
+            None => return,
+        };
+
+        match fs::write(path, self.save_state()) {
+            Ok(()) => info!("Saved state to {}", path.display()),
+            Err(e) => error!("Unable to save state to {}: {}", path.display(), e),
+        }
+    }
+
+    // Restore the save state from the state file, failures are only logged
+    fn load_state_file(&mut self) {
+        let path = match &self.state_path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+
+        let loaded = fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| self.load_state(&data).map_err(|e| e.to_string()));
+
+        match loaded {
+            Ok(()) => info!("Loaded state from {}", path.display()),
+            Err(e) => error!("Unable to load state from {}: {}", path.display(), e),
+        }
+    }
+
+    // Pass sampled keys through the input delay queue into the input register
+    fn push_input(&mut self, keys: u16) {
+        self.input_queue.push_back(keys);
+        let previous = self.keyboard_input;
+        self.keyboard_input = self.input_queue.pop_front().unwrap_or(0);
+
+        let pressed = self.keyboard_input & !previous;
+        let released = previous & !self.keyboard_input;
+
+        // A press stays pending for Fx0A until it is consumed or the key is released
+        self.pressed_keys = (self.pressed_keys | pressed) & self.keyboard_input;
+
+        for key in 0..16 {
+            if released & 1 << key != 0 {
+                trace!("Key {:X} released", key);
+            }
+
+            if pressed & 1 << key != 0 {
+                trace!("Key {:X} pressed", key);
+            }
+        }
+    }
+
+    // Check whether a key is held, values above 0xF never are
+    fn is_key_down(&self, key: u8) -> bool {
+        key < 16 && self.keyboard_input & 1 << key != 0
+    }
+
+    // Append the registers at the end of the current frame to the register log
+    fn log_registers(&mut self) {
+        let writer = match self.register_log.as_mut() {
+            Some(writer) => writer,
+            None => return,
+        };
+
+        let mut row = format!("{},{}", self.frame_count, self.program_counter);
+        for value in &self.v_registers {
+            row.push_str(&format!(",{}", value));
+        }
+        row.push_str(&format!(
+            ",{},{},{}",
+            self.index_register, self.delay_timer, self.sound_timer
+        ));
+
+        if let Err(e) = writeln!(writer, "{}", row) {
+            warn!("Stopped writing register log: {}", e);
+            self.register_log = None;
+        }
+    }
+
+    // Tick frame timer, returns whether a frame was due and advanced
+    fn tick_frame(&mut self) -> bool {
+        let now = Instant::now();
+
+        if self.next_frame_tick > now {
+            return false;
+        }
+
+        if let Some(last_frame_start) = self.last_frame_start {
+            self.frame_stats.record(now.sub(last_frame_start));
+        }
+        self.last_frame_start = Some(now);
+
+        self.log_registers();
+        self.cycles_in_current_frame = 0;
+        self.draws_in_current_frame = 0;
+        self.frame_count += 1;
+        if self.count_frame_skip() {
+            self.periphery.draw_screen();
+        }
+        self.next_frame_tick = now.add(self.frame_interval());
+
+        true
+    }
+
+    // Tick both timers at 60Hz. This runs on its own wall-clock schedule on purpose: the frame
+    // rate may be changed or uncapped, but programs count on the timers to measure real time,
+    // so timers must never be derived from frames.
+    fn tick_timers(&mut self) {
+        let now = Instant::now();
+
+        if self.next_timer_tick <= now {
+            self.decrement_timers();
+            self.next_timer_tick = now.add(TIMER_INTERVAL);
+        }
+    }
+
+    // Decrement both timers by one tick, stop sound once the sound timer ran out. Called once
+    // per emulated frame, so sound outputs are told about the frame here as well. This is the
+    // only place timers count down, so a timer never drops more than once per 1/60s of
+    // virtual time no matter how many cycles run per frame, and a value set with Fx15/Fx18
+    // reads back unchanged for the rest of the frame.
+    fn decrement_timers(&mut self) {
+        if self.delay_timer != 0 {
+            self.delay_timer -= 1;
+        }
+
+        if self.sound_timer != 0 {
+            self.sound_timer -= 1;
+        }
+
+        // The beep may outlast the sound timer, stop it once it ran out as well
+        if self.beep_ticks != 0 {
+            self.beep_ticks -= 1;
+        } else {
+            self.periphery.stop_sound();
+        }
+
+        self.periphery.end_audio_frame();
+    }
+
+    // Finish an emulated frame without waiting for wall-clock time. Emulated frames last one
+    // timer tick and timers tick once per emulated frame, so game logic keeps its pace relative
+    // to the CPU, the screen is still only drawn at the regular frame rate.
+    fn fast_forward_frame(&mut self) {
+        self.log_registers();
+        self.cycles_in_current_frame = 0;
+        self.draws_in_current_frame = 0;
+        self.frame_count += 1;
+        self.decrement_timers();
+
+        let now = Instant::now();
+
+        if self.next_frame_tick <= now {
+            self.periphery.draw_screen();
+            self.next_frame_tick = now.add(self.frame_interval());
+        }
+    }
+
+    // Sleep if needed (we assume a 1ms accuracy of the sleep timer)
+    fn sleep_if_needed(&mut self) {
+        let now = Instant::now();
+
+        if now < self.next_frame_tick {
+            let until = self.next_frame_tick.sub(now);
+
+            if until > Duration::from_millis(1) {
+                sleep(until.sub(Duration::from_millis(1)));
+            }
+        }
+    }
+
+    fn unknown_opcode(&self, opcode: u16) -> EmulationError {
+        EmulationError::UnknownOpcode {
+            opcode,
+            pc: self.program_counter,
+        }
+    }
+
+    // Get the memory range Fx55/Fx65 transfer V0..=VX to or from, fails if it runs past the
+    // end of memory
+    fn register_file_range(&self, opcode: u16) -> Result<Range<usize>, EmulationError> {
+        self.index_range(opcode, to_usize(second_nibble(opcode)) + 1)
+    }
+
+    // Get the memory range of length bytes starting at the index register, fails if it runs
+    // past the end of memory
+    fn index_range(&self, opcode: u16, length: usize) -> Result<Range<usize>, EmulationError> {
+        let start = usize::from(self.index_register);
+        let end = start + length;
+
+        if end > MEMORY_SIZE {
+            return Err(EmulationError::MemoryOutOfBounds {
+                opcode,
+                pc: self.program_counter,
+                start,
+                end,
+            });
+        }
+
+        Ok(start..end)
+    }
+}
+
+// Get roughly how many machine cycles the COSMAC VIP interpreter spends on an instruction
+// other than a draw, taken skips cost a little more
+fn vip_cycles(opcode: u16, skipped: bool) -> u32 {
+    let skip = if skipped { 4 } else { 0 };
+    let registers = u32::from(second_nibble(opcode)) + 1;
+
+    match (first_nibble(opcode), lower_half(opcode)) {
+        (0x0, 0xE0) => 24,
+        (0x0, 0xEE) => 10,
+        (0x0, _) => 26,
+        (0x1, _) | (0xA, _) => 12,
+        (0x2, _) => 26,
+        (0x3, _) | (0x4, _) => 10 + skip,
+        (0x5, _) | (0x9, _) | (0xE, _) => 14 + skip,
+        (0x6, _) => 6,
+        (0x7, _) => 10,
+        (0x8, _) => 20,
+        (0xB, _) => 22,
+        (0xC, _) => 36,
+        (0xF, 0x1E) | (0xF, 0x29) => 16,
+        (0xF, 0x33) => 80,
+        (0xF, 0x55) | (0xF, 0x65) => 14 + 14 * registers,
+        _ => 10,
+    }
+}
+
+// Get the beep frequency for an XO-CHIP pitch. Pitch 64 plays the default beep and 48 steps
+// make an octave, like the XO-CHIP pattern playback rate.
+fn pitch_frequency(pitch: u8) -> u32 {
+    let octaves = (f32::from(pitch) - f32::from(DEFAULT_PITCH)) / 48.0;
+    (BEEP_FREQ as f32 * octaves.exp2()).round() as u32
+}
+
+// Parse hex bytes separated by whitespace like `F0 0x90`, as searched for by the pause prompt
+fn parse_byte_pattern(text: &str) -> Result<Vec<u8>, String> {
+    let pattern = text
+        .split_whitespace()
+        .map(|byte| {
+            let digits = byte
+                .strip_prefix("0x")
+                .or_else(|| byte.strip_prefix("0X"))
+                .unwrap_or(byte);
+            u8::from_str_radix(digits, 16).map_err(|_| format!("Invalid byte: {}", byte))
+        })
+        .collect::<Result<Vec<u8>, String>>()?;
+
+    if pattern.is_empty() {
+        return Err(String::from("Nothing to find, give the bytes as hex"));
+    }
+
+    Ok(pattern)
+}
+
+// Check whether a load range intersects the reserved fontset region, including the large font
+fn overlaps_fontset(offset: usize, length: usize) -> bool {
+    let fontset_start = usize::from(FONTSET_OFFSET);
+    let fontset_end = usize::from(LARGE_FONTSET_OFFSET) + LARGE_FONTSET_SIZE;
+
+    length > 0 && offset < fontset_end && offset + length > fontset_start
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::Audio;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Place a single opcode at the program counter and execute it
+    fn execute(system: &mut System, opcode: u16) {
+        try_execute(system, opcode).unwrap();
+    }
+
+    // Like execute, but returns why the opcode could not be executed
+    fn try_execute(system: &mut System, opcode: u16) -> Result<(), EmulationError> {
+        system.memory[system.program_counter] = (opcode >> 8) as u8;
+        system.memory[system.program_counter + 1] = (opcode & 0xff) as u8;
+        system.cycle()
+    }
+
+    #[test]
+    fn test_add_with_vf_as_destination() {
+        let mut system = System::headless();
+        system.v_registers[15] = 0xff;
+        system.v_registers[0] = 0x01;
+        execute(&mut system, 0x8F04);
+        assert_eq!(system.v_registers[15], 1);
+
+        system.v_registers[15] = 0x01;
+        system.v_registers[0] = 0x01;
+        execute(&mut system, 0x8F04);
+        assert_eq!(system.v_registers[15], 0);
+    }
+
+    #[test]
+    fn test_add_with_vf_as_source() {
+        let mut system = System::headless();
+        system.v_registers[0] = 0xff;
+        system.v_registers[15] = 0x01;
+        execute(&mut system, 0x80F4);
+        assert_eq!(system.v_registers[0], 0x00);
+        assert_eq!(system.v_registers[15], 1);
+    }
+
+    #[test]
+    fn test_add_with_vf_as_both_operands() {
+        let mut system = System::headless();
+        system.v_registers[15] = 0x80;
+        execute(&mut system, 0x8FF4);
+        assert_eq!(system.v_registers[15], 1);
+
+        system.v_registers[15] = 0x01;
+        execute(&mut system, 0x8FF4);
+        assert_eq!(system.v_registers[15], 0);
+    }
+
+    #[test]
+    fn test_subtract_with_vf_as_destination() {
+        let mut system = System::headless();
+        system.v_registers[15] = 0x05;
+        system.v_registers[0] = 0x03;
+        execute(&mut system, 0x8F05);
+        assert_eq!(system.v_registers[15], 1);
+
+        system.v_registers[15] = 0x05;
+        system.v_registers[0] = 0x03;
+        execute(&mut system, 0x80F7);
+        assert_eq!(system.v_registers[0], 0x02);
+        assert_eq!(system.v_registers[15], 1);
+    }
+
+    #[test]
+    fn test_draw_stops_at_end_of_memory() {
+        let mut system = System::headless();
+        system.index_register = 0xFFA;
+        for address in 0xFFA..MEMORY_SIZE {
+            system.memory[address] = 0xff;
+        }
+
+        execute(&mut system, 0xD00F);
+
+        for y in 0..15 {
+            let expected = if y < 6 { 1 } else { 0 };
+            assert_eq!(system.periphery.framebuffer[y * 64], expected);
+        }
+        assert_eq!(system.v_registers[15], 0);
+        assert_eq!(system.program_counter, 0x202);
+    }
+
+    #[test]
+    fn test_fill_uninitialized_memory() {
+        let system = System::with_uninitialized_memory(Periphery::headless(), 0xAA);
+
+        assert_eq!(system.memory[0x000], 0xAA);
+        assert_eq!(system.memory[0x04F], 0xAA);
+        assert_eq!(system.memory[0x050], 0xF0);
+        assert_eq!(system.memory[0x09F], 0x80);
+        assert_eq!(system.memory[0x0A0], 0xFF);
+        assert_eq!(system.memory[0x103], 0xFF);
+        assert_eq!(system.memory[0x104], 0xAA);
+        assert_eq!(system.memory[0x200], 0xAA);
+        assert_eq!(system.memory[MEMORY_SIZE - 1], 0xAA);
+    }
+
+    #[test]
+    fn test_fill_uninitialized_memory_randomly_is_reproducible() {
+        let first = System::with_random_memory(Periphery::headless(), 42);
+        let mut second = System::with_random_memory(Periphery::headless(), 42);
+
+        assert!(first.memory.iter().eq(second.memory.iter()));
+
+        // Another seed fills memory differently
+        second.fill_memory_randomly(43);
+        assert!(!first.memory.iter().eq(second.memory.iter()));
+    }
+
+    #[test]
+    fn test_shift_in_place() {
+        let mut system = System::headless();
+        system.v_registers[1] = 0b0000_0101;
+        system.v_registers[2] = 0b1000_0010;
+
+        execute(&mut system, 0x8126);
+        assert_eq!(system.v_registers[1], 0b0000_0010);
+        assert_eq!(system.v_registers[15], 1);
+
+        execute(&mut system, 0x812E);
+        assert_eq!(system.v_registers[1], 0b0000_0100);
+        assert_eq!(system.v_registers[15], 0);
+        assert_eq!(system.v_registers[2], 0b1000_0010);
+    }
+
+    #[test]
+    fn test_shift_amount() {
+        let mut system = System::headless();
+        system.set_shift_amount(3);
+        system.v_registers[1] = 0b0110_0100;
+
+        execute(&mut system, 0x8106);
+        assert_eq!(system.v_registers[1], 0b0000_1100);
+        assert_eq!(system.v_registers[15], 1);
+
+        execute(&mut system, 0x810E);
+        assert_eq!(system.v_registers[1], 0b0110_0000);
+        assert_eq!(system.v_registers[15], 0);
+
+        system.v_registers[1] = 0b0010_0000;
+        execute(&mut system, 0x810E);
+        assert_eq!(system.v_registers[1], 0b0000_0000);
+        assert_eq!(system.v_registers[15], 1);
+    }
+
+    #[test]
+    fn test_shift_uses_vy() {
+        let mut system = System::headless();
+        system.set_quirk_shift_uses_vy(true);
+        system.v_registers[1] = 0b0000_0101;
+        system.v_registers[2] = 0b1000_0010;
+
+        execute(&mut system, 0x8126);
+        assert_eq!(system.v_registers[1], 0b0100_0001);
+        assert_eq!(system.v_registers[15], 0);
+
+        execute(&mut system, 0x812E);
+        assert_eq!(system.v_registers[1], 0b0000_0100);
+        assert_eq!(system.v_registers[15], 1);
+        assert_eq!(system.v_registers[2], 0b1000_0010);
+    }
+
+    #[test]
+    fn test_flag_registers() {
+        let path = std::env::temp_dir().join("chirpy-test-flags");
+        let _ = fs::remove_file(&path);
+
+        let mut system = System::headless();
+        system.set_flags_path(Some(path.clone()));
+        system.v_registers[..4].copy_from_slice(&[1, 2, 3, 4]);
+        execute(&mut system, 0xF275);
+
+        system.v_registers[..4].copy_from_slice(&[9, 9, 9, 9]);
+        execute(&mut system, 0xF385);
+        assert_eq!(system.v_registers[..4], [1, 2, 3, 0]);
+
+        // The flags survive a restart
+        let mut restarted = System::headless();
+        restarted.set_flags_path(Some(path.clone()));
+        execute(&mut restarted, 0xF185);
+        assert_eq!(restarted.v_registers[..2], [1, 2]);
+
+        assert_eq!(
+            try_execute(&mut restarted, 0xF875),
+            Err(EmulationError::UnknownOpcode {
+                opcode: 0xF875,
+                pc: 0x202
+            })
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_jump_quirk() {
+        let mut system = System::headless();
+        system.v_registers[0] = 0x10;
+        system.v_registers[3] = 0x20;
+
+        execute(&mut system, 0xB300);
+        assert_eq!(system.program_counter, 0x310);
+
+        system.set_quirk_jump_uses_vx(true);
+        execute(&mut system, 0xB300);
+        assert_eq!(system.program_counter, 0x320);
+    }
+
+    #[test]
+    fn test_store_bcd() {
+        let mut system = System::headless();
+        system.index_register = 0x300;
+        system.v_registers[2] = 207;
+        execute(&mut system, 0xF233);
+        assert_eq!(&system.memory[0x300..0x303], &[2, 0, 7]);
+
+        // The digits have to fit into memory, I may point anywhere after F000 NNNN
+        for &index in &[0xFFF, 0xFFFF] {
+            system.index_register = index;
+            assert_eq!(
+                try_execute(&mut system, 0xF233),
+                Err(EmulationError::MemoryOutOfBounds {
+                    opcode: 0xF233,
+                    pc: system.program_counter,
+                    start: usize::from(index),
+                    end: usize::from(index) + 3
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_platform() {
+        assert_eq!(Platform::parse("SCHIP"), Ok(Platform::SuperChip));
+        assert_eq!(Platform::parse("xo-chip"), Ok(Platform::XoChip));
+        assert!(Platform::parse("megachip").is_err());
+
+        let mut system = System::headless();
+        system.set_platform(Platform::Chip8);
+        assert!(system.quirk_shift_uses_vy);
+        assert!(system.quirk_memory_increments_index);
+        assert!(!system.quirk_jump_uses_vx);
+        assert!(system.quirk_display_wait);
+
+        system.set_platform(Platform::SuperChip);
+        assert!(!system.quirk_shift_uses_vy);
+        assert!(!system.quirk_memory_increments_index);
+        assert!(system.quirk_jump_uses_vx);
+        assert!(!system.quirk_display_wait);
+    }
+
+    #[test]
+    fn test_memory_quirk() {
+        let mut system = System::headless();
+        system.v_registers[..3].copy_from_slice(&[1, 2, 3]);
+        system.index_register = 0x300;
+
+        execute(&mut system, 0xF255);
+        assert_eq!(system.memory[0x300..0x303], [1, 2, 3]);
+        assert_eq!(system.index_register, 0x300);
+
+        system.set_quirk_memory_increments_index(true);
+        execute(&mut system, 0xF255);
+        assert_eq!(system.index_register, 0x303);
+
+        system.index_register = 0x300;
+        execute(&mut system, 0xF065);
+        assert_eq!(system.v_registers[0], 1);
+        assert_eq!(system.index_register, 0x301);
+    }
+
+    #[test]
+    fn test_machine_code_ignore() {
+        let mut system = System::headless();
+        execute(&mut system, 0x0123);
+        assert_eq!(system.program_counter, 0x202);
+        assert!(!system.halted);
+    }
+
+    #[test]
+    fn test_machine_code_strict() {
+        let mut system = System::headless();
+        system.set_machine_code_policy(MachineCodePolicy::Strict);
+        assert_eq!(
+            try_execute(&mut system, 0x0123),
+            Err(EmulationError::UnknownOpcode {
+                opcode: 0x0123,
+                pc: 0x200
+            })
+        );
+    }
+
+    #[test]
+    fn test_machine_code_halt() {
+        let mut system = System::headless();
+        system.set_machine_code_policy(MachineCodePolicy::Halt);
+        execute(&mut system, 0x0123);
+        assert_eq!(system.program_counter, 0x200);
+        assert!(system.halted);
+    }
+
+    #[test]
+    fn test_clock_speed() {
+        let mut system = System::headless();
+        system.set_clock_speed(600);
+        assert_eq!(system.cycles_per_frame(), 10);
+
+        system.memory[0x200] = 0x12;
+        system.memory[0x201] = 0x00;
+        system.run_cycles(31);
+        assert_eq!(system.frame_count(), 3);
+
+        system.set_clock_speed(0);
+        assert_eq!(system.clock_speed(), 1);
+        assert_eq!(system.cycles_per_frame(), 1);
+
+        system.set_clock_speed(50_000);
+        assert_eq!(system.clock_speed(), 10_000);
+        assert_eq!(system.cycles_per_frame(), 166);
+    }
+
+    #[test]
+    fn test_frame_rate() {
+        let mut system = System::headless();
+        system.set_clock_speed(1_000);
+        assert_eq!(system.cycles_per_frame(), 16);
+        assert_eq!(system.frame_interval(), Duration::from_nanos(16_666_666));
+
+        system.set_frame_rate(50);
+        assert_eq!(system.cycles_per_frame(), 20);
+        assert_eq!(system.frame_interval(), Duration::from_millis(20));
+        assert_eq!(system.frame_stats().target, Duration::from_millis(20));
+
+        // Timers keep their pace in virtual time
+        system
+            .copy_buffer_to_memory(vec![0x12, 0x00], 0x200)
+            .unwrap();
+        system.delay_timer = 10;
+        system.run_cycles(16 * 3);
+        assert_eq!(system.delay_timer, 7);
+
+        system.set_frame_rate(0);
+        assert_eq!(system.cycles_per_frame(), 16);
+        assert_eq!(system.frame_interval(), Duration::default());
+    }
+
+    #[test]
+    fn test_frame_skip() {
+        let mut system = System::headless();
+        let drawn: Vec<bool> = (0..3).map(|_| system.count_frame_skip()).collect();
+        assert_eq!(drawn, vec![true, true, true]);
+
+        system.set_frame_skip(2);
+        let drawn: Vec<bool> = (0..6).map(|_| system.count_frame_skip()).collect();
+        assert_eq!(drawn, vec![false, false, true, false, false, true]);
+    }
+
+    #[test]
+    fn test_parse_clock_speed() {
+        assert_eq!(System::parse_clock_speed("700"), Ok(700));
+        assert_eq!(System::parse_clock_speed("10000"), Ok(10_000));
+        assert_eq!(
+            System::parse_clock_speed("fast"),
+            Err(String::from("Invalid clock speed: fast"))
+        );
+        assert_eq!(
+            System::parse_clock_speed("99"),
+            Err(String::from(
+                "Clock speed must be between 100 and 10000 Hz: 99"
+            ))
+        );
+        assert!(System::parse_clock_speed("10001").is_err());
+    }
+
+    #[test]
+    fn test_frame_count() {
+        let mut system = System::headless();
+        system.memory[0x200] = 0x12;
+        system.memory[0x201] = 0x00;
+
+        system.run_cycles(u64::from(system.cycles_per_tick()) * 3 + 1);
+        assert_eq!(system.frame_count(), 3);
+
+        // Loading a state and resetting count from 0 again
+        let state = system.save_state();
+        system.run_cycles(u64::from(system.cycles_per_tick()));
+        system.load_state(&state).unwrap();
+        assert_eq!(system.frame_count(), 0);
+
+        system.run_cycles(u64::from(system.cycles_per_tick()));
+        system.reset();
+        assert_eq!(system.frame_count(), 0);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut system = System::headless();
+        system
+            .copy_buffer_to_memory(vec![0x00, 0xFF, 0x60, 0x05, 0xF0, 0x29, 0xD0, 0x05], 0x200)
+            .unwrap();
+        system.run_cycles(4);
+        execute(&mut system, 0x2300);
+        system.delay_timer = 9;
+
+        system.reset();
+        assert_eq!(system.program_counter, 0x200);
+        assert!(system.stack.is_empty());
+        assert_eq!(system.v_registers, [0; 16]);
+        assert_eq!(system.delay_timer, 0);
+        assert!(!system.periphery.is_hires());
+        assert!(system.framebuffer().iter().all(|pixel| *pixel == 0));
+        // The program stays loaded
+        assert_eq!(system.memory[0x201], 0xFF);
+    }
+
+    #[test]
+    fn test_draw_start_wraps_around_resolution() {
+        let mut system = System::headless();
+        system.index_register = 0x300;
+        system.memory[0x300] = 0x80;
+        system.v_registers[0] = 70;
+        system.v_registers[1] = 33;
+
+        execute(&mut system, 0xD011);
+
+        assert_eq!(system.periphery.framebuffer[64 + 6], 1);
+        assert_eq!(
+            system
+                .periphery
+                .framebuffer
+                .iter()
+                .filter(|pixel| **pixel != 0)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_zero_height_draw_empty() {
+        let mut system = System::headless();
+        system.index_register = 0x300;
+        system.memory[0x300] = 0xFF;
+        system.v_registers[15] = 1;
+
+        execute(&mut system, 0xD000);
+
+        assert!(system.periphery.framebuffer.iter().all(|pixel| *pixel == 0));
+        assert_eq!(system.v_registers[15], 0);
+        assert_eq!(system.program_counter, 0x202);
+    }
+
+    #[test]
+    fn test_zero_height_draw_error() {
+        let mut system = System::headless();
+        system.set_zero_height_draw_policy(ZeroHeightDrawPolicy::Error);
+        let error = try_execute(&mut system, 0xD000).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Zero height draw: 0xD000 at address 0x200!"
+        );
+        assert_eq!(system.program_counter, 0x200);
+    }
+
+    #[test]
+    fn test_zero_height_draw_large() {
+        let mut system = System::headless();
+        system.set_zero_height_draw_policy(ZeroHeightDrawPolicy::Large);
+        system.index_register = 0x300;
+        // Top row 0x8001, bottom row 0xFFFF
+        system.memory[0x300] = 0x80;
+        system.memory[0x301] = 0x01;
+        system.memory[0x31E] = 0xFF;
+        system.memory[0x31F] = 0xFF;
+
+        execute(&mut system, 0xD000);
+
+        let framebuffer = system.framebuffer();
+        assert_eq!(framebuffer[0], 1);
+        assert_eq!(framebuffer[1], 0);
+        assert_eq!(framebuffer[15], 1);
+        assert!((0..16).all(|x| framebuffer[15 * 64 + x] == 1));
+        assert_eq!(framebuffer[15 * 64 + 16], 0);
+        assert_eq!(system.v_registers[15], 0);
+
+        // Drawing again erases the sprite and reports the collision
+        system.program_counter = 0x200;
+        execute(&mut system, 0xD000);
+        assert!(system.periphery.framebuffer.iter().all(|pixel| *pixel == 0));
+        assert_eq!(system.v_registers[15], 1);
+    }
+
+    #[test]
+    fn test_long_index() {
+        let mut system = System::headless();
+        system
+            .copy_buffer_to_memory(vec![0xF0, 0x00, 0x12, 0x34], 0x200)
+            .unwrap();
+        system.cycle().unwrap();
+        assert_eq!(system.index_register, 0x1234);
+        assert_eq!(system.program_counter, 0x204);
+
+        // Skips jump over the address word too
+        system.program_counter = 0x300;
+        system.memory[0x302..0x306].copy_from_slice(&[0xF0, 0x00, 0x12, 0x34]);
+        execute(&mut system, 0x3000);
+        assert_eq!(system.program_counter, 0x306);
+
+        // The address word has to be in memory
+        system.program_counter = MEMORY_SIZE - 2;
+        assert_eq!(
+            try_execute(&mut system, 0xF000),
+            Err(EmulationError::MemoryOutOfBounds {
+                opcode: 0xF000,
+                pc: MEMORY_SIZE - 2,
+                start: MEMORY_SIZE,
+                end: MEMORY_SIZE + 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_draw_to_selected_planes() {
+        let mut system = System::headless();
+        system.index_register = 0x300;
+        system.memory[0x300] = 0xC0;
+        system.memory[0x301] = 0x60;
+
+        // A sprite only lands on the second plane while it is the only one selected
+        execute(&mut system, 0xF201);
+        execute(&mut system, 0xD001);
+        assert_eq!(system.framebuffer()[..4], [2, 2, 0, 0]);
+        assert_eq!(system.v_registers[15], 0);
+
+        // With both planes selected the second plane gets the next sprite, only the collision
+        // on the second plane sets VF
+        execute(&mut system, 0xF301);
+        execute(&mut system, 0xD001);
+        assert_eq!(system.framebuffer()[..4], [3, 1, 2, 0]);
+        assert_eq!(system.v_registers[15], 1);
+
+        // 00E0 only clears the selected planes
+        execute(&mut system, 0xF101);
+        execute(&mut system, 0x00E0);
+        assert_eq!(system.framebuffer()[..4], [2, 0, 2, 0]);
+    }
+
+    #[test]
+    fn test_zero_height_draw_hires() {
+        let mut system = System::headless();
+        execute(&mut system, 0x00FF);
+        system.index_register = 0x300;
+        // A 16x16 frame, rows 0 and 15 solid, the rows between only have the outer pixels set
+        for row in 0..16 {
+            let (left, right) = if row == 0 || row == 15 {
+                (0xFF, 0xFF)
+            } else {
+                (0x80, 0x01)
+            };
+            system.memory[0x300 + row * 2] = left;
+            system.memory[0x301 + row * 2] = right;
+        }
+        system.v_registers[0] = 100;
+        system.v_registers[1] = 40;
+
+        execute(&mut system, 0xD010);
+
+        let lit: Vec<usize> = (0..HIRES_SIZE)
+            .filter(|index| system.framebuffer()[*index] != 0)
+            .collect();
+        assert_eq!(lit.len(), 16 * 2 + 14 * 2);
+        assert!((100..116).all(|x| system.framebuffer()[40 * 128 + x] == 1));
+        assert!((100..116).all(|x| system.framebuffer()[55 * 128 + x] == 1));
+        assert_eq!(system.framebuffer()[47 * 128 + 100], 1);
+        assert_eq!(system.framebuffer()[47 * 128 + 101], 0);
+        assert_eq!(system.framebuffer()[47 * 128 + 115], 1);
+        assert_eq!(system.v_registers[15], 0);
+
+        // Overlapping the bottom right corner only clears the shared pixel and sets VF
+        system.v_registers[0] = 115;
+        system.v_registers[1] = 55;
+        execute(&mut system, 0xD010);
+        assert_eq!(system.framebuffer()[55 * 128 + 115], 0);
+        assert_eq!(system.framebuffer()[55 * 128 + 116], 1);
+        assert_eq!(system.v_registers[15], 1);
+    }
+
+    #[test]
+    fn test_on_fetch_replaces_opcode() {
+        let mut system = System::headless();
+        system.set_on_fetch(|address, opcode| if address == 0x200 { 0x6007 } else { opcode });
+
+        execute(&mut system, 0x6005);
+        assert_eq!(system.v_registers[0], 7);
+
+        execute(&mut system, 0x6105);
+        assert_eq!(system.v_registers[1], 5);
+    }
+
+    #[test]
+    fn test_jump_into_reserved_region() {
+        let mut system = System::headless();
+        system.set_executable_range(Some(System::program_range()));
+        execute(&mut system, 0x1100);
+        assert_eq!(
+            system.cycle(),
+            Err(EmulationError::OutsideExecutableRegion {
+                pc: 0x100,
+                start: 0x200,
+                end: MEMORY_SIZE - 1
+            })
+        );
+
+        // The last byte holds no whole opcode
+        system.program_counter = 0x200;
+        execute(&mut system, 0x1FFF);
+        assert!(matches!(
+            system.cycle(),
+            Err(EmulationError::OutsideExecutableRegion { pc: 0xFFF, .. })
+        ));
+    }
+
+    #[test]
+    fn test_fetch_beyond_memory() {
+        // Jumping to the last byte, BNNN past the end and running off the end all fail to fetch
+        let mut system = System::headless();
+        execute(&mut system, 0x1FFF);
+        assert_eq!(
+            system.cycle(),
+            Err(EmulationError::MemoryOutOfBounds {
+                opcode: 0,
+                pc: 0xFFF,
+                start: 0xFFF,
+                end: 0x1001
+            })
+        );
+
+        let mut system = System::headless();
+        execute(&mut system, 0x60FF);
+        execute(&mut system, 0xBFFF);
+        assert_eq!(system.program_counter, 0x10FE);
+        assert!(system.cycle().is_err());
+
+        let mut system = System::headless();
+        system.program_counter = MEMORY_SIZE - 2;
+        execute(&mut system, 0x6000);
+        assert_eq!(
+            system.cycle(),
+            Err(EmulationError::MemoryOutOfBounds {
+                opcode: 0,
+                pc: MEMORY_SIZE,
+                start: MEMORY_SIZE,
+                end: MEMORY_SIZE + 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_jump_into_reserved_region_unchecked() {
+        let mut system = System::headless();
+        execute(&mut system, 0x1100);
+        assert_eq!(system.program_counter, 0x100);
+        system.cycle().unwrap();
+    }
+
+    #[test]
+    fn test_boot_delay() {
+        let mut system = System::headless();
+        system.set_boot_delay(Duration::from_millis(20));
+        system.set_start_paused(true);
+
+        let start = Instant::now();
+        system.boot();
+
+        // Without a window there is no key to wait for
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(system.next_frame_tick >= start + Duration::from_millis(20));
+        assert_eq!(system.program_counter, 0x200);
+    }
+
+    #[test]
+    fn test_frame_stats() {
+        let mut stats = FrameStats {
+            target: Duration::from_nanos(16_666_667),
+            ..FrameStats::default()
+        };
+        assert_eq!(stats.average(), Duration::default());
+
+        stats.record(Duration::from_millis(16));
+        stats.record(Duration::from_millis(20));
+        stats.record(Duration::from_millis(18));
+
+        assert_eq!(stats.frames, 3);
+        assert_eq!(stats.min, Duration::from_millis(16));
+        assert_eq!(stats.max, Duration::from_millis(20));
+        assert_eq!(stats.average().as_micros(), 18_000);
+        assert_eq!(stats.standard_deviation().as_micros(), 1_632);
+        assert_eq!(
+            stats.to_string(),
+            "3 frame intervals: min 16.00ms, max 20.00ms, avg 18.00ms, stddev 1.63ms (target 16.67ms)"
+        );
+    }
+
+    #[test]
+    fn test_timer_stats() {
+        let mut system = System::headless();
+        system.v_registers[0] = 30;
+        system.v_registers[1] = 4;
+        execute(&mut system, 0xF015);
+        execute(&mut system, 0xF115);
+        execute(&mut system, 0xF118);
+
+        let stats = system.timer_stats();
+        assert_eq!(stats.delay_timer_sets, 2);
+        assert_eq!(stats.delay_timer_max, 30);
+        assert_eq!(stats.sound_timer_sets, 1);
+        assert_eq!(stats.sound_timer_max, 4);
+        assert_eq!(
+            stats.to_string(),
+            "delay timer set 2 times, max value 30; sound timer set 1 times, max value 4"
+        );
+    }
+
+    #[test]
+    fn test_input_without_delay() {
+        let mut system = System::headless();
+        system.push_input(1 << 0x5);
+        assert_eq!(system.keyboard_input, 1 << 0x5);
+    }
+
+    #[test]
+    fn test_input_delay() {
+        let mut system = System::headless();
+        system.set_input_delay(2);
+
+        system.push_input(1 << 0x5);
+        assert_eq!(system.keyboard_input, 0);
+        system.push_input(0);
+        assert_eq!(system.keyboard_input, 0);
+        system.push_input(0);
+        assert_eq!(system.keyboard_input, 1 << 0x5);
+        system.push_input(0);
+        assert_eq!(system.keyboard_input, 0);
+    }
+
+    #[test]
+    fn test_input_delay_in_run_loop() {
+        let mut system = System::headless();
+        system.set_input_delay(2);
+        system.push_input(1 << 0x5);
+
+        // Like run, finish frames in a busy loop, the key shows up two frames later only
+        let mut inputs = vec![];
+        while inputs.len() < 3 {
+            let frame_count = system.frame_count;
+            system.finish_frame();
+            if system.frame_count != frame_count {
+                inputs.push(system.keyboard_input);
+            }
+        }
+        assert_eq!(inputs, vec![0, 1 << 0x5, 0]);
+    }
+
+    #[test]
+    fn test_scroll_down() {
+        let mut system = System::headless();
+        let width = usize::from(crate::periphery::SCREEN_WIDTH);
+        // A diagonal line from the top left corner and a pixel in the bottom row
+        for y in 0..8 {
+            system.periphery.framebuffer[y * width + y] = 1;
+        }
+        system.periphery.framebuffer[SCREEN_SIZE - 1] = 1;
+
+        execute(&mut system, 0x00C4);
+        assert_eq!(system.program_counter, 0x202);
+
+        let framebuffer = system.framebuffer();
+        assert!(framebuffer[..4 * width].iter().all(|pixel| *pixel == 0));
+        for y in 0..8 {
+            assert_eq!(framebuffer[(y + 4) * width + y], 1);
+            assert_eq!(framebuffer[(y + 4) * width + y + 1], 0);
+        }
+        assert_eq!(framebuffer.iter().filter(|pixel| **pixel != 0).count(), 8);
+    }
+
+    #[test]
+    fn test_scroll_horizontally() {
+        let mut system = System::headless();
+        let width = usize::from(crate::periphery::SCREEN_WIDTH);
+        // The two outermost pixels of the first two rows
+        for index in &[0, width - 1, width, 2 * width - 1] {
+            system.periphery.framebuffer[*index] = 1;
+        }
+
+        // Pixels at the right edge are lost instead of moving into the next row
+        execute(&mut system, 0x00FB);
+        let lit: Vec<usize> = (0..SCREEN_SIZE)
+            .filter(|index| system.framebuffer()[*index] != 0)
+            .collect();
+        assert_eq!(lit, vec![4, width + 4]);
+
+        // Scrolling back left does not bring them back
+        execute(&mut system, 0x00FC);
+        let lit: Vec<usize> = (0..SCREEN_SIZE)
+            .filter(|index| system.framebuffer()[*index] != 0)
+            .collect();
+        assert_eq!(lit, vec![0, width]);
+
+        // Pixels at the left edge are lost as well
+        execute(&mut system, 0x00FC);
+        assert!(system.framebuffer().iter().all(|pixel| *pixel == 0));
+        assert_eq!(system.program_counter, 0x206);
+    }
+
+    #[test]
+    fn test_scroll_in_hires() {
+        let mut system = System::headless();
+        execute(&mut system, 0x00FF);
+        let width = usize::from(crate::periphery::HIRES_WIDTH);
+        // A pixel beyond the low resolution and the bottom right corner
+        system.periphery.framebuffer[10 * width + 100] = 1;
+        system.periphery.framebuffer[HIRES_SIZE - 1] = 1;
+
+        // Distances are high resolution pixels, rows and columns span the 128x64 screen
+        execute(&mut system, 0x00C8);
+        let lit: Vec<usize> = (0..HIRES_SIZE)
+            .filter(|index| system.framebuffer()[*index] != 0)
+            .collect();
+        assert_eq!(lit, vec![18 * width + 100]);
+
+        execute(&mut system, 0x00FB);
+        assert_eq!(system.framebuffer()[18 * width + 104], 1);
+
+        execute(&mut system, 0x00FC);
+        execute(&mut system, 0x00FC);
+        assert_eq!(system.framebuffer()[18 * width + 96], 1);
+        assert_eq!(
+            system
+                .framebuffer()
+                .iter()
+                .filter(|pixel| **pixel != 0)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_load_too_large() {
+        let mut system = System::headless();
+        assert_eq!(
+            system.copy_buffer_to_memory(vec![0; 3_585], 0x200),
+            Err(LoadError::TooLarge {
+                size: 3_585,
+                available: 3_584
+            })
+        );
+        assert_eq!(system.memory[0x200], 0);
+        assert!(system.copy_buffer_to_memory(vec![0; 3_584], 0x200).is_ok());
+
+        system.set_fontset_write_protection(true);
+        assert_eq!(
+            system.copy_buffer_to_memory(vec![0; 2], 0x50),
+            Err(LoadError::FontsetOverlap { offset: 0x50 })
+        );
+    }
+
+    #[test]
+    fn test_switch_resolution() {
+        let mut system = System::headless();
+        execute(&mut system, 0x00FF);
+        assert_eq!(system.resolution(), (128, 64));
+        assert_eq!(system.framebuffer().len(), 128 * 64);
+
+        // Draws wrap around the high resolution
+        system.v_registers[0] = 124;
+        system.v_registers[1] = 63;
+        execute(&mut system, 0xA000 | FONTSET_OFFSET);
+        execute(&mut system, 0xD011);
+        assert_eq!(system.framebuffer()[63 * 128 + 124..], [1, 1, 1, 1]);
+
+        execute(&mut system, 0x00FE);
+        assert_eq!(system.resolution(), (64, 32));
+        assert_eq!(system.framebuffer().len(), SCREEN_SIZE);
+        assert_eq!(system.program_counter, 0x208);
+    }
+
+    #[test]
+    fn test_clear_display_keeps_cpu_state() {
+        let mut system = System::headless();
+        system.periphery.framebuffer[10] = 1;
+        system.v_registers[3] = 7;
+
+        system.clear_display();
+
+        assert!(system.periphery.framebuffer.iter().all(|pixel| *pixel == 0));
+        assert_eq!(system.v_registers[3], 7);
+        assert_eq!(system.program_counter, 0x200);
+    }
+
+    #[test]
+    fn test_mode_assertions() {
+        let mut system = System::headless();
+        system.set_test_mode(true);
+
+        execute(&mut system, 0x0F03);
+        assert_eq!(system.test_results().failures, vec![(0x200, 3)]);
+        assert!(!system.halted);
+
+        execute(&mut system, 0x0F00);
+        assert!(system.test_results().passed);
+        assert!(system.halted);
+    }
+
+    #[test]
+    fn test_mode_disabled_ignores_assertions() {
+        let mut system = System::headless();
+        execute(&mut system, 0x0F03);
+        assert!(system.test_results().failures.is_empty());
+        assert_eq!(system.program_counter, 0x202);
+    }
+
+    #[test]
+    fn test_on_step_reports_executed_instructions() {
+        let steps = Rc::new(RefCell::new(vec![]));
+        let recorded = Rc::clone(&steps);
+
+        let mut system = System::headless();
+        system.set_on_step(move |step| recorded.borrow_mut().push(*step));
+        execute(&mut system, 0x6007);
+        execute(&mut system, 0x1300);
+
+        assert_eq!(
+            *steps.borrow(),
+            vec![
+                StepInfo {
+                    address: 0x200,
+                    opcode: 0x6007,
+                    next_address: 0x202,
+                    frame: 0,
+                },
+                StepInfo {
+                    address: 0x202,
+                    opcode: 0x1300,
+                    next_address: 0x300,
+                    frame: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_store_all_registers() {
+        let mut system = System::headless();
+        for (index, register) in system.v_registers.iter_mut().enumerate() {
+            *register = index as u8 + 1;
+        }
+        system.index_register = 0x300;
+        system.memory[0x310] = 0xAA;
+
+        execute(&mut system, 0xFF55);
+        assert_eq!(system.memory[0x300..0x310], system.v_registers);
+        assert_eq!(system.memory[0x310], 0xAA);
+        assert_eq!(system.index_register, 0x300);
+    }
+
+    #[test]
+    fn test_load_all_registers() {
+        let mut system = System::headless();
+        for (index, byte) in system.memory[0x300..0x311].iter_mut().enumerate() {
+            *byte = index as u8 + 1;
+        }
+        system.index_register = 0x300;
+
+        execute(&mut system, 0xFF65);
+        assert_eq!(system.v_registers, system.memory[0x300..0x310]);
+        assert_eq!(system.index_register, 0x300);
+
+        // Only V0..=VX are loaded
+        system.v_registers = [0; 16];
+        execute(&mut system, 0xF265);
+        assert_eq!(system.v_registers[..4], [1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn test_store_registers_past_memory_end() {
+        let mut system = System::headless();
+        system.index_register = 0xFF8;
+        let error = try_execute(&mut system, 0xFF55).unwrap_err();
+        assert!(error.to_string().ends_with("beyond the end of memory!"));
+    }
+
+    #[test]
+    fn test_run_until_register_breakpoint() {
+        let mut system = System::headless();
+        // V3 counts down from 3, V4 counts up
+        system
+            .copy_buffer_to_memory(vec![0x63, 0x03, 0x73, 0xFF, 0x74, 0x01, 0x12, 0x02], 0x200)
+            .unwrap();
+        system.break_when_register(4, 5);
+        system.break_when_register(3, 0);
+
+        let fired = system.run_until_breakpoint(100);
+        assert_eq!(
+            fired,
+            Some(RegisterCondition {
+                register: 3,
+                value: 0
+            })
+        );
+        assert_eq!(system.program_counter, 0x204);
+        assert_eq!(system.v_registers[4], 2);
+    }
+
+    #[test]
+    fn test_memory_slice() {
+        let system = System::headless();
+        assert_eq!(
+            system.memory_slice(0x50..0x55),
+            &[0xF0, 0x90, 0x90, 0x90, 0xF0]
+        );
+        assert_eq!(
+            system.memory_slice(MEMORY_SIZE - 2..MEMORY_SIZE + 2).len(),
+            2
+        );
+        assert!(system
+            .memory_slice(MEMORY_SIZE + 1..MEMORY_SIZE + 2)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_step() {
+        let mut system = System::headless();
+        system
+            .copy_buffer_to_memory(vec![0x60, 0x2A, 0x12, 0x04, 0xFF, 0xFF], 0x200)
+            .unwrap();
+
+        system.step().unwrap();
+        assert_eq!(system.program_counter, 0x202);
+        assert_eq!(system.v_registers[0], 0x2A);
+
+        system.step().unwrap();
+        assert_eq!(system.program_counter, 0x204);
+
+        // Stepping onto an unknown opcode fails without moving on or halting
+        assert!(system.step().is_err());
+        assert_eq!(system.program_counter, 0x204);
+        assert!(!system.halted);
+    }
+
+    #[test]
+    fn test_run_until_breakpoint_without_hit() {
+        let mut system = System::headless();
+        system
+            .copy_buffer_to_memory(vec![0x12, 0x00], 0x200)
+            .unwrap();
+        system.break_when_register(0, 1);
+        assert_eq!(system.run_until_breakpoint(10), None);
+    }
+
+    #[test]
+    fn test_call_graph() {
+        let mut system = System::headless();
+        execute(&mut system, 0x2300);
+        assert_eq!(system.call_graph(), None);
+
+        system.set_call_graph_recording(true);
+        system.program_counter = 0x200;
+        system.stack.clear();
+        execute(&mut system, 0x2300);
+        execute(&mut system, 0x2400);
+
+        let call_graph = system.call_graph().unwrap();
+        assert_eq!(
+            call_graph.calls.iter().cloned().collect::<Vec<_>>(),
+            vec![(0x200, 0x300), (0x300, 0x400)]
+        );
+        assert_eq!(
+            call_graph.to_dot(),
+            "digraph calls {\n    \"0x200\" -> \"0x300\";\n    \"0x300\" -> \"0x400\";\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_alignment_check_warns_once() {
+        let mut system = System::headless();
+        system.set_alignment_check(true);
+
+        execute(&mut system, 0x1301);
+        assert!(system.misaligned_addresses.is_empty());
+
+        execute(&mut system, 0x1301);
+        execute(&mut system, 0x1301);
+        assert_eq!(
+            system
+                .misaligned_addresses
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>(),
+            vec![0x301]
+        );
+        assert_eq!(system.program_counter, 0x301);
+    }
+
+    #[test]
+    fn test_find_in_memory() {
+        let mut system = System::headless();
+        system
+            .copy_buffer_to_memory(vec![0xDE, 0xAD, 0xDE, 0xAD], 0x300)
+            .unwrap();
+        system
+            .copy_buffer_to_memory(vec![0xDE, 0xAD], 0xFFE)
+            .unwrap();
+
+        assert_eq!(
+            system.find_in_memory(&[0xDE, 0xAD]),
+            vec![0x300, 0x302, 0xFFE]
+        );
+        assert_eq!(system.find_in_memory(&[0xAD, 0xDE, 0xAD]), vec![0x301]);
+        assert!(system.find_in_memory(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_parse_byte_pattern() {
+        assert_eq!(
+            parse_byte_pattern("F0 0x90  ff"),
+            Ok(vec![0xF0, 0x90, 0xFF])
+        );
+        assert_eq!(
+            parse_byte_pattern("F0 100"),
+            Err(String::from("Invalid byte: 100"))
+        );
+        assert!(parse_byte_pattern(" ").is_err());
+    }
+
+    // Call the next instruction a number of times, nesting one level deeper each time
+    fn nest_calls(system: &mut System, levels: usize) {
+        for _ in 0..levels {
+            let target = system.program_counter + 2;
+            execute(system, 0x2000 | target as u16);
+        }
+    }
+
+    #[test]
+    fn test_stack_nesting_to_limit() {
+        let mut system = System::headless();
+        nest_calls(&mut system, 16);
+        assert_eq!(system.stack.len(), 16);
+        assert_eq!(system.stack[0], 0x202);
+
+        execute(&mut system, 0x00EE);
+        assert_eq!(system.program_counter, 0x220);
+    }
+
+    #[test]
+    fn test_stack_overflow_error() {
+        let mut system = System::headless();
+        nest_calls(&mut system, 16);
+        assert_eq!(
+            try_execute(&mut system, 0x2224),
+            Err(EmulationError::StackOverflow {
+                pc: 0x220,
+                stack_size: 16
+            })
+        );
+    }
+
+    #[test]
+    fn test_stack_overflow_wrap() {
+        let mut system = System::headless();
+        system.set_stack_size(2);
+        system.set_stack_overflow_policy(StackOverflowPolicy::Wrap);
+        nest_calls(&mut system, 3);
+        assert_eq!(system.stack, vec![0x204, 0x206]);
+        assert_eq!(system.program_counter, 0x206);
+    }
+
+    #[test]
+    fn test_stack_overflow_ignore() {
+        let mut system = System::headless();
+        system.set_stack_size(2);
+        system.set_stack_overflow_policy(StackOverflowPolicy::Ignore);
+        nest_calls(&mut system, 3);
+        assert_eq!(system.stack, vec![0x202, 0x204]);
+        assert_eq!(system.program_counter, 0x206);
+    }
+
+    #[test]
+    fn test_25_nested_calls() {
+        // Every subroutine calls the next one right away
+        let rom: Vec<u8> = (0..25u16)
+            .flat_map(|level| {
+                let target = 0x2202 + level * 2;
+                vec![(target >> 8) as u8, (target & 0xff) as u8]
+            })
+            .collect();
+
+        // The first call uses the first slot and the 17th call overflows the default stack
+        let mut system = System::headless();
+        system.copy_buffer_to_memory(rom.clone(), 0x200).unwrap();
+        system.run_cycles(25);
+        assert_eq!(system.stack.len(), 16);
+        assert_eq!(system.stack[0], 0x202);
+        assert_eq!(
+            system.error(),
+            Some(EmulationError::StackOverflow {
+                pc: 0x220,
+                stack_size: 16
+            })
+        );
+
+        let mut system = System::headless();
+        system.set_stack_size(25);
+        system.copy_buffer_to_memory(rom, 0x200).unwrap();
+        system.run_cycles(25);
+        assert_eq!(system.stack.len(), 25);
+        assert_eq!(system.stack[24], 0x232);
+        assert_eq!(system.error(), None);
+    }
+
+    #[test]
+    fn test_return_without_call() {
+        let mut system = System::headless();
+        assert_eq!(
+            try_execute(&mut system, 0x00EE),
+            Err(EmulationError::StackUnderflow { pc: 0x200 })
+        );
+    }
+
+    #[test]
+    fn test_skip_unknown_opcode() {
+        let mut system = System::headless();
+        system.set_unknown_opcode_policy(UnknownOpcodePolicy::Skip);
+        system
+            .copy_buffer_to_memory(vec![0x60, 0x01, 0xFF, 0xFF, 0x60, 0x02], 0x200)
+            .unwrap();
+        system.run_cycles(3);
+
+        assert!(!system.halted);
+        assert_eq!(system.error(), None);
+        assert_eq!(system.v_registers[0], 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown opcode")]
+    fn test_strict_panics() {
+        let mut system = System::headless();
+        system.set_strict(true);
+        system
+            .copy_buffer_to_memory(vec![0xFF, 0xFF], 0x200)
+            .unwrap();
+        system.run_cycles(1);
+    }
+
+    #[test]
+    fn test_error_halts_run() {
+        let mut system = System::headless();
+        system
+            .copy_buffer_to_memory(vec![0x60, 0x01, 0xFF, 0xFF, 0x60, 0x02], 0x200)
+            .unwrap();
+        system.run_cycles(10);
+
+        assert!(system.halted);
+        assert_eq!(
+            system.error(),
+            Some(EmulationError::UnknownOpcode {
+                opcode: 0xFFFF,
+                pc: 0x202
+            })
+        );
+        assert_eq!(system.v_registers[0], 1);
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let mut system = System::headless();
+        system
+            .copy_buffer_to_memory(vec![0x12, 0x00, 0x00, 0xE0], 0x200)
+            .unwrap();
+        execute(&mut system, 0x2300);
+
+        let memory_map = system.memory_map();
+        let regions: Vec<(&str, Range<usize>)> = memory_map
+            .regions
+            .iter()
+            .map(|region| (region.name, region.range.clone()))
+            .collect();
+
+        assert_eq!(
+            regions,
+            vec![
+                ("reserved for the interpreter", 0x000..0x050),
+                ("fontset", 0x050..0x0A0),
+                ("large fontset", 0x0A0..0x104),
+                ("reserved for the interpreter", 0x104..0x200),
+                ("ROM", 0x200..0x204),
+                ("free", 0x204..0x1000),
+            ]
+        );
+        assert_eq!(memory_map.stack_depth, 1);
+        assert!(memory_map
+            .to_string()
+            .contains("0x200-0x203     4 bytes  ROM"));
+    }
+
+    #[test]
+    fn test_state_json() {
+        let mut system = System::headless();
+        system.v_registers[0xF] = 1;
+        execute(&mut system, 0xA123);
+        execute(&mut system, 0x2300);
+
+        let state: serde_json::Value = serde_json::from_str(&system.state_json()).unwrap();
+        assert_eq!(state["version"], 1);
+        assert_eq!(state["pc"], 0x300);
+        assert_eq!(state["i"], 0x123);
+        assert_eq!(state["sp"], 1);
+        assert_eq!(state["stack"], serde_json::json!([0x204]));
+        assert_eq!(state["v"][15], 1);
+
+        let memory = base64::decode(state["memory"].as_str().unwrap()).unwrap();
+        assert_eq!(memory.len(), MEMORY_SIZE);
+        assert_eq!(memory[0x202..0x204], [0x23, 0x00]);
+    }
+
+    #[test]
+    fn test_load_state() {
+        let mut system = System::headless();
+        system.v_registers[0x3] = 7;
+        system.delay_timer = 9;
+        execute(&mut system, 0xA123);
+        execute(&mut system, 0x2300);
+        let json = system.state_json();
+
+        let mut restored = System::headless();
+        restored.load_state_json(&json).unwrap();
+        assert_eq!(restored.state_json(), json);
+        assert_eq!(restored.program_counter, 0x300);
+        assert_eq!(restored.stack, vec![0x204]);
+        assert_eq!(restored.v_registers[0x3], 7);
+        assert_eq!(restored.delay_timer, 9);
+    }
+
+    #[test]
+    fn test_load_corrupted_state() {
+        let mut system = System::headless();
+        let valid: serde_json::Value = serde_json::from_str(&system.state_json()).unwrap();
+        let corrupted = |field: &str, value: serde_json::Value| {
+            let mut state = valid.clone();
+            state[field] = value;
+            state.to_string()
+        };
+
+        let reason = |result: Result<(), LoadError>| match result {
+            Err(LoadError::InvalidState(reason)) => reason,
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(()) => panic!("corrupted state was loaded"),
+        };
+
+        assert!(system.load_state_json("{\"version\": 1, \"pc\"").is_err());
+        assert_eq!(
+            reason(system.load_state_json(&corrupted("version", 2.into()))),
+            "unsupported version 2"
+        );
+        assert_eq!(
+            reason(system.load_state_json(&corrupted("memory", base64::encode([0; 16]).into()))),
+            "memory has 16 bytes instead of 4096"
+        );
+        assert_eq!(
+            reason(system.load_state_json(&corrupted("pc", 0xFFF.into()))),
+            "pc 0xFFF is outside of memory"
+        );
+        assert_eq!(
+            reason(system.load_state_json(&corrupted("sp", 3.into()))),
+            "sp 3 does not match 0 stack entries"
+        );
+        assert_eq!(
+            reason(system.load_state_json(&corrupted("stack", serde_json::json!([0x1000])))),
+            "sp 0 does not match 1 stack entries"
+        );
+        assert!(system
+            .load_state_json(&corrupted("v", serde_json::json!([0, 1, 2])))
+            .is_err());
+
+        let mut deep = valid.clone();
+        deep["sp"] = 17.into();
+        deep["stack"] = serde_json::json!(vec![0x200; 17]);
+        assert_eq!(
+            reason(system.load_state_json(&deep.to_string())),
+            "stack holds 17 return addresses, at most 16 fit"
+        );
+
+        let mut outside = valid;
+        outside["sp"] = 1.into();
+        outside["stack"] = serde_json::json!([0x1000]);
+        assert_eq!(
+            reason(system.load_state_json(&outside.to_string())),
+            "return address 0x1000 is outside of memory"
+        );
+
+        // Nothing was changed by the failed loads
+        assert_eq!(system.program_counter, 0x200);
+        assert!(system.stack.is_empty());
+    }
+
+    #[test]
+    fn test_save_state() {
+        let (mut system, calls) = system_recording_audio();
+        system
+            .copy_buffer_to_memory(vec![0x00, 0xFF, 0x60, 0x05, 0xF0, 0x29, 0xD0, 0x05], 0x200)
+            .unwrap();
+        system.delay_timer = 9;
+        system.run_cycles(4);
+        execute(&mut system, 0x2300);
+        execute(&mut system, 0xF201);
+        system.v_registers[1] = 112;
+        execute(&mut system, 0xF13A);
+        execute(&mut system, 0xF175);
+        system.sound_timer = 5;
+        let saved = system.save_state();
+        let screen = system.framebuffer().to_vec();
+
+        execute(&mut system, 0x00FE);
+        execute(&mut system, 0xF101);
+        system.index_register = 0x200;
+        execute(&mut system, 0xF002);
+        system.v_registers[1] = 64;
+        execute(&mut system, 0xF13A);
+        execute(&mut system, 0xF175);
+        execute(&mut system, 0x6A42);
+        system.memory[0x300] = 0xAB;
+        system.delay_timer = 0;
+        system.sound_timer = 0;
+
+        calls.borrow_mut().clear();
+        system.load_state(&saved).unwrap();
+        assert_eq!(system.save_state(), saved);
+        assert!(system.periphery.is_hires());
+        assert_eq!(system.program_counter, 0x306);
+        assert_eq!(system.stack, vec![0x20A]);
+        assert_eq!(system.periphery.selected_planes(), 2);
+        assert_eq!(system.pitch, 112);
+        assert_eq!(system.periphery.beep_frequency(), 880);
+        assert_eq!(system.periphery.audio_pattern(), None);
+        assert_eq!(system.rpl_flags[..2], [5, 112]);
+        assert_eq!(system.v_registers[0xA], 0);
+        assert_eq!(system.delay_timer, 9);
+        assert_eq!(system.framebuffer(), &screen[..]);
+        assert!(screen.contains(&1));
+
+        // The restored sound timer beeps, restoring a silent state stops the beep
+        assert_eq!(system.beep_ticks, 5);
+        assert_eq!(calls.borrow().last(), Some(&"play"));
+        system.load_state(&System::headless().save_state()).unwrap();
+        assert_eq!(system.beep_ticks, 0);
+        assert_eq!(calls.borrow().last(), Some(&"stop"));
+    }
+
+    #[test]
+    fn test_load_corrupted_save_state() {
+        let mut system = System::headless();
+        let valid = system.save_state();
+
+        let reason = |result: Result<(), LoadError>| match result {
+            Err(LoadError::InvalidState(reason)) => reason,
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(()) => panic!("corrupted state was loaded"),
+        };
+
+        assert_eq!(reason(system.load_state(b"C8")), "state is truncated");
+        assert_eq!(
+            reason(system.load_state(&valid[..valid.len() - 1])),
+            "state is truncated"
+        );
+        assert_eq!(reason(system.load_state(b"GIF89a")), "not a save state");
+
+        let mut version = valid.clone();
+        version[4] = 1;
+        assert_eq!(reason(system.load_state(&version)), "unsupported version 1");
+
+        let mut planes = valid.clone();
+        planes[30] = 4;
+        assert_eq!(reason(system.load_state(&planes)), "invalid planes 4");
+
+        let mut pc = valid.clone();
+        pc[5..7].copy_from_slice(&[0x0F, 0xFF]);
+        assert_eq!(
+            reason(system.load_state(&pc)),
+            "pc 0xFFF is outside of memory"
+        );
+
+        let mut trailing = valid;
+        trailing.push(0);
+        assert_eq!(
+            reason(system.load_state(&trailing)),
+            "1 bytes left after the state"
+        );
+
+        assert_eq!(system.program_counter, 0x200);
+    }
+
+    #[test]
+    fn test_wait_for_key_completes_on_release() {
+        let mut system = System::headless();
+        // Wait for a key into V0 and count accepted keys in V1, forever
+        system
+            .copy_buffer_to_memory(vec![0xF0, 0x0A, 0x71, 0x01, 0x12, 0x00], 0x200)
+            .unwrap();
+        // Headless input reads as no key at the end of a frame, so never finish one
+        system.set_cycles_per_frame(1_000);
+
+        // Holding a key keeps waiting
+        for _ in 0..10 {
+            system.push_input(1 << 0x5);
+            system.run_cycles(20);
+        }
+        assert_eq!(system.v_registers[1], 0);
+        assert_eq!(system.program_counter, 0x200);
+
+        // Releasing it stores the key once
+        system.push_input(0);
+        system.run_cycles(20);
+        assert_eq!(system.v_registers[0], 0x5);
+        assert_eq!(system.v_registers[1], 1);
+
+        // Switching directly to another key releases the first one
+        system.push_input(1 << 0x7);
+        system.run_cycles(20);
+        system.push_input(1 << 0x8);
+        system.run_cycles(20);
+        assert_eq!(system.v_registers[0], 0x7);
+        assert_eq!(system.v_registers[1], 2);
+
+        // The key switched to counts as a new press
+        system.push_input(0);
+        system.run_cycles(20);
+        assert_eq!(system.v_registers[0], 0x8);
+        assert_eq!(system.v_registers[1], 3);
+    }
+
+    #[test]
+    fn test_skip_with_multiple_keys_held() {
+        let mut system = System::headless();
+        system.push_input(1 << 0x2 | 1 << 0x4);
+        system.v_registers[0] = 0x2;
+        system.v_registers[1] = 0x4;
+        system.v_registers[2] = 0x6;
+        system.v_registers[3] = 0x12;
+
+        execute(&mut system, 0xE09E);
+        assert_eq!(system.program_counter, 0x204);
+        execute(&mut system, 0xE19E);
+        assert_eq!(system.program_counter, 0x208);
+        execute(&mut system, 0xE2A1);
+        assert_eq!(system.program_counter, 0x20C);
+        // Only the lower nibble is a key, 0x12 is never held
+        execute(&mut system, 0xE3A1);
+        assert_eq!(system.program_counter, 0x210);
+    }
+
+    #[test]
+    fn test_wait_for_key_picks_new_press() {
+        let mut system = System::headless();
+        system
+            .copy_buffer_to_memory(vec![0xF0, 0x0A, 0x71, 0x01, 0x12, 0x00], 0x200)
+            .unwrap();
+        system.set_cycles_per_frame(1_000);
+
+        // Of two keys pressed together the lower one is picked
+        system.push_input(1 << 0xA | 1 << 0xB);
+        system.run_cycles(20);
+        system.push_input(1 << 0xB);
+        system.run_cycles(20);
+        assert_eq!(system.v_registers[0], 0xA);
+        assert_eq!(system.v_registers[1], 1);
+
+        // Pressing C while B is still held is seen
+        system.push_input(1 << 0xB | 1 << 0xC);
+        system.run_cycles(20);
+        system.push_input(1 << 0xB);
+        system.run_cycles(20);
+        assert_eq!(system.v_registers[0], 0xC);
+        assert_eq!(system.v_registers[1], 2);
+    }
+
+    #[test]
+    fn test_draw_small_font_glyph() {
+        let mut system = System::headless();
+        system.v_registers[0] = 0x8;
+        execute(&mut system, 0xF029);
+        execute(&mut system, 0xD115);
+
+        // Glyph 8 is 0xF0, 0x90, 0xF0, 0x90, 0xF0 and nothing is drawn below it
+        let rows = |system: &System, left: usize, top: usize| -> Vec<u8> {
+            let (width, _) = system.resolution();
+            (top..top + 6)
+                .map(|y| {
+                    (left..left + 8).fold(0, |row, x| {
+                        row << 1 | system.framebuffer()[y * usize::from(width) + x]
+                    })
+                })
+                .collect()
+        };
+        assert_eq!(
+            rows(&system, 0, 0),
+            vec![0xF0, 0x90, 0xF0, 0x90, 0xF0, 0x00]
+        );
+        assert_eq!(system.v_registers[15], 0);
+
+        // In high resolution the small glyph keeps its size and lands on 128x64 coordinates
+        execute(&mut system, 0x00FF);
+        system.v_registers[2] = 100;
+        system.v_registers[3] = 50;
+        execute(&mut system, 0xD235);
+        assert_eq!(
+            rows(&system, 100, 50),
+            vec![0xF0, 0x90, 0xF0, 0x90, 0xF0, 0x00]
+        );
+        let lit = system.framebuffer().iter().filter(|pixel| **pixel != 0);
+        assert_eq!(lit.count(), 16);
+        assert_eq!(system.v_registers[15], 0);
+    }
+
+    #[test]
+    fn test_set_large_font() {
+        let mut system = System::headless();
+        system.v_registers[0] = 0;
+        system.v_registers[1] = 7;
+        execute(&mut system, 0xF030);
+        assert_eq!(system.index_register, 0xA0);
+
+        execute(&mut system, 0xF130);
+        assert_eq!(system.index_register, 0xA0 + 70);
+        let glyph = usize::from(system.index_register);
+        assert_eq!(
+            system.memory[glyph..glyph + 10],
+            [0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18]
+        );
+
+        // There is no large digit beyond 9
+        system.v_registers[2] = 0xFF;
+        let error = try_execute(&mut system, 0xF230).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid large digit: 0xF230 at address 0x204 selects digit 255!"
+        );
+        assert_eq!(system.index_register, 0xA0 + 70);
+    }
+
+    #[test]
+    fn test_max_draws_per_frame() {
+        let mut system = System::headless();
+        system.set_max_draws_per_frame(Some(2));
+        system.set_cycles_per_frame(10);
+        // Draw forever, counting draws in V1
+        system
+            .copy_buffer_to_memory(vec![0xD0, 0x01, 0x71, 0x01, 0x12, 0x00], 0x200)
+            .unwrap();
+
+        system.run_cycles(10);
+        assert_eq!(system.v_registers[1], 2);
+        assert_eq!(system.program_counter, 0x200);
+
+        system.run_cycles(10);
+        assert_eq!(system.v_registers[1], 4);
+
+        // A limit of 0 must not stall every draw
+        system.set_max_draws_per_frame(Some(0));
+        system.run_cycles(9);
+        assert_eq!(system.v_registers[1], 7);
+    }
+
+    #[test]
+    fn test_display_wait() {
+        let mut system = System::headless();
+        system.set_quirk_display_wait(true);
+        system.set_cycles_per_frame(10);
+        // Draw forever, counting draws in V1
+        system
+            .copy_buffer_to_memory(vec![0xD0, 0x01, 0x71, 0x01, 0x12, 0x00], 0x200)
+            .unwrap();
+
+        // Every draw waits for the next frame, so ten instructions span three frames
+        system.run_cycles(10);
+        assert_eq!(system.v_registers[1], 2);
+        assert_eq!(system.frame_count(), 3);
+        assert_eq!(system.program_counter, 0x202);
+    }
+
+    #[test]
+    fn test_display_wait_draws_once_per_frame() {
+        let mut system = System::headless();
+        system.set_quirk_display_wait(true);
+        system.set_cycles_per_frame(100);
+        // Nothing but draws
+        system
+            .copy_buffer_to_memory(vec![0xD0, 0x01, 0xD0, 0x01, 0x12, 0x00], 0x200)
+            .unwrap();
+
+        system.run_cycles(1_000);
+        assert!(system.draw_count > 0);
+        assert!(system.draw_count <= system.frame_count());
+    }
+
+    #[test]
+    fn test_vip_timing_draw_cycles() {
+        let mut system = System::headless();
+        system.set_vip_timing(true);
+        execute(&mut system, 0x6005);
+        assert_eq!(system.cycle_cost, 6);
+
+        // Waiting for the display uses up the rest of the frame
+        system.cycles_in_current_frame = 1_000;
+        execute(&mut system, 0xD01F);
+        assert_eq!(system.cycle_cost, VIP_CYCLES_PER_TICK - 1_000);
+        assert_eq!(system.program_counter, 0x202);
+
+        // The draw itself costs per row once it runs
+        system.cycles_in_current_frame = 0;
+        execute(&mut system, 0xD01F);
+        assert_eq!(system.cycle_cost, 22 + 15 * 34);
+        assert_eq!(system.program_counter, 0x204);
+    }
+
+    #[test]
+    fn test_f_family_decodes_both_nibbles() {
+        let mut system = System::headless();
+        system.delay_timer = 0x42;
+        execute(&mut system, 0xF107);
+        assert_eq!(system.v_registers[1], 0x42);
+    }
+
+    #[test]
+    fn test_f100_is_not_f000() {
+        let mut system = System::headless();
+        assert!(try_execute(&mut system, 0xF100).is_err());
+    }
+
+    #[test]
+    fn test_f_family_rejects_unknown_lower_half() {
+        let mut system = System::headless();
+        assert!(try_execute(&mut system, 0xF0FF).is_err());
+    }
+
+    #[test]
+    fn test_delay_timer_ticks_once_per_frame_at_high_clock() {
+        let mut system = System::headless();
+        system.set_cycles_per_frame(10_000);
+        // Set the delay timer to 1, then read it back into V1 forever
+        system
+            .copy_buffer_to_memory(vec![0x60, 0x01, 0xF0, 0x15, 0xF1, 0x07, 0x12, 0x04], 0x200)
+            .unwrap();
+
+        system.run_cycles(9_999);
+        assert_eq!(system.delay_timer, 1);
+        assert_eq!(system.v_registers[1], 1);
+
+        // The frame ends after the next cycle, V1 picks up the new value right after
+        system.run_cycles(1);
+        assert_eq!(system.delay_timer, 0);
+        system.run_cycles(2);
+        assert_eq!(system.v_registers[1], 0);
+    }
+
+    // Writer which keeps what was written readable by the test
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_register_log() {
+        let buffer = Rc::new(RefCell::new(vec![]));
+        let mut system = System::headless();
+        system.set_cycles_per_frame(1);
+        system.set_register_log(Box::new(SharedBuffer(buffer.clone())));
+        // V3 = 0x2A, I = 0x300, DT = V3
+        system
+            .copy_buffer_to_memory(vec![0x63, 0x2A, 0xA3, 0x00, 0xF3, 0x15], 0x200)
+            .unwrap();
+
+        system.run_cycles(3);
+
+        let log = String::from_utf8(buffer.borrow().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(
+            lines[0],
+            "frame,pc,v0,v1,v2,v3,v4,v5,v6,v7,v8,v9,va,vb,vc,vd,ve,vf,i,dt,st"
+        );
+        assert_eq!(lines[1], "0,514,0,0,0,42,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0");
+        assert_eq!(lines[2], "1,516,0,0,0,42,0,0,0,0,0,0,0,0,0,0,0,0,768,0,0");
+        assert_eq!(lines[3], "2,518,0,0,0,42,0,0,0,0,0,0,0,0,0,0,0,0,768,42,0");
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn test_trace_unique() {
+        let buffer = Rc::new(RefCell::new(vec![]));
+        let mut system = System::headless();
+        system.set_trace_unique(Box::new(SharedBuffer(buffer.clone())));
+        system
+            .copy_buffer_to_memory(vec![0x60, 0x01, 0x60, 0x01, 0x61, 0x02], 0x200)
+            .unwrap();
+
+        system.run_cycles(3);
+
+        let trace = String::from_utf8(buffer.borrow().clone()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("0x200: 6001"));
+        assert!(lines[1].starts_with("0x204: 6102"));
+    }
+
+    // Audio output which records play and stop calls
+    struct RecordingAudio {
+        calls: Rc<RefCell<Vec<&'static str>>>,
+    }
+
+    impl Audio for RecordingAudio {
+        fn play(&mut self) {
+            self.calls.borrow_mut().push("play");
+        }
+
+        fn stop(&mut self) {
+            self.calls.borrow_mut().push("stop");
+        }
+
+        fn play_stereo_patterns(&mut self, _: &[u8; 16], _: &[u8; 16], _: f32) {}
+
+        fn play_pattern(&mut self, _: &[u8; 16], _: u32) {
+            self.calls.borrow_mut().push("pattern");
+        }
+
+        fn set_beep_frequency(&mut self, _: u32) {
+            self.calls.borrow_mut().push("pitch");
+        }
+
+        fn end_frame(&mut self) {
+            self.calls.borrow_mut().push("frame");
+        }
+    }
+
+    #[test]
+    fn test_set_pitch() {
+        let (mut system, calls) = system_recording_audio();
+        system.v_registers[1] = 112;
+        execute(&mut system, 0xF13A);
+        assert_eq!(system.periphery.beep_frequency(), 880);
+
+        // The default pitch plays the default beep
+        system.v_registers[1] = 64;
+        execute(&mut system, 0xF13A);
+        assert_eq!(system.periphery.beep_frequency(), 440);
+        execute(&mut system, 0xF13A);
+        assert_eq!(*calls.borrow(), vec!["pitch", "pitch"]);
+    }
+
+    #[test]
+    fn test_load_audio_pattern() {
+        let (mut system, calls) = system_recording_audio();
+        system.index_register = 0x300;
+        system.memory[0x300..0x310].copy_from_slice(&[0xF0; 16]);
+        execute(&mut system, 0xF002);
+        assert_eq!(system.periphery.audio_pattern(), Some([0xF0; 16]));
+
+        // The pitch keeps playing the pattern, only faster
+        system.v_registers[1] = 112;
+        execute(&mut system, 0xF13A);
+        assert_eq!(*calls.borrow(), vec!["pattern", "pattern"]);
+
+        // All 16 bytes have to be in memory
+        system.index_register = 0xFF8;
+        assert!(matches!(
+            try_execute(&mut system, 0xF002),
+            Err(EmulationError::MemoryOutOfBounds {
+                start: 0xFF8,
+                end: 0x1008,
+                ..
+            })
+        ));
+    }
+
+    // Create a headless system which records its audio calls
+    fn system_recording_audio() -> (System, Rc<RefCell<Vec<&'static str>>>) {
+        let calls = Rc::new(RefCell::new(vec![]));
+        let mut periphery = Periphery::headless();
+        periphery.add_audio(Box::new(RecordingAudio {
+            calls: Rc::clone(&calls),
+        }));
+
+        (System::with_periphery(periphery), calls)
+    }
+
+    #[test]
+    fn test_single_tick_beep_plays() {
+        let (mut system, calls) = system_recording_audio();
+        system.v_registers[0] = 1;
+        execute(&mut system, 0xF018);
+        system.decrement_timers();
+        system.decrement_timers();
+
+        assert_eq!(*calls.borrow(), vec!["play", "frame", "stop", "frame"]);
+    }
+
+    #[test]
+    fn test_min_beep_ticks() {
+        let (mut system, calls) = system_recording_audio();
+        system.set_min_beep_ticks(3);
+        system.v_registers[0] = 1;
+        execute(&mut system, 0xF018);
+        for _ in 0..4 {
+            system.decrement_timers();
+        }
+
+        assert_eq!(
+            *calls.borrow(),
+            vec!["play", "frame", "frame", "frame", "stop", "frame"]
+        );
+        assert_eq!(system.sound_timer, 0);
+    }
+
+    #[test]
+    fn test_run_until_draw() {
+        let mut system = System::headless();
+        // Draw the glyph for 0 twice, moving right in between, then spin
+        system
+            .copy_buffer_to_memory(
+                vec![
+                    0x60, 0x00, 0xF0, 0x29, 0xD1, 0x25, 0x71, 0x08, 0xD1, 0x25, 0x12, 0x0A,
+                ],
+                0x200,
+            )
+            .unwrap();
+
+        assert!(system.run_until_draw(100));
+        assert_eq!(system.program_counter, 0x206);
+        assert_eq!(system.framebuffer()[..8], [1, 1, 1, 1, 0, 0, 0, 0]);
+        assert_eq!(system.framebuffer()[8], 0);
+
+        assert!(system.run_until_draw(100));
+        assert_eq!(system.framebuffer()[8..16], [1, 1, 1, 1, 0, 0, 0, 0]);
+
+        assert!(!system.run_until_draw(100));
     }
 }
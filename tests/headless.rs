@@ -0,0 +1,70 @@
+// Run ROMs through the library without a window or audio device, like CI does
+use chirpy::system::EmulationError;
+use chirpy::System;
+
+// Read a row of 8 pixels starting at (x, y) as a byte
+fn framebuffer_row(system: &System, x: usize, y: usize) -> u8 {
+    let width = usize::from(chirpy::periphery::SCREEN_WIDTH);
+    let framebuffer = system.framebuffer();
+
+    (0..8).fold(0, |row, offset| {
+        row << 1 | (framebuffer[y * width + x + offset] != 0) as u8
+    })
+}
+
+#[test]
+fn test_draw_glyph_headless() {
+    let mut system = System::headless();
+    // V0 = 5, I = glyph 5, draw it at (V0, V0), loop forever
+    system
+        .copy_buffer_to_memory(vec![0x60, 0x05, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x06], 0x200)
+        .unwrap();
+
+    for _ in 0..3 {
+        system.cycle().unwrap();
+    }
+
+    let rows: Vec<u8> = (5..11).map(|y| framebuffer_row(&system, 5, y)).collect();
+    assert_eq!(rows, vec![0xF0, 0x80, 0xF0, 0x10, 0xF0, 0x00]);
+
+    // Running on only loops, without waiting for a window or timers
+    system.run_cycles(1_000);
+    assert_eq!(framebuffer_row(&system, 5, 5), 0xF0);
+    assert_eq!(system.error(), None);
+}
+
+#[test]
+fn test_inspect_state_headless() {
+    let mut system = System::headless();
+    // V0 = 0x2A, I = 0x300, delay timer = V0
+    system
+        .copy_buffer_to_memory(vec![0x60, 0x2A, 0xA3, 0x00, 0xF0, 0x15], 0x200)
+        .unwrap();
+
+    system.step().unwrap();
+    assert_eq!(system.v_register(0), 0x2A);
+    assert_eq!(system.program_counter(), 0x202);
+
+    system.step().unwrap();
+    system.step().unwrap();
+    assert_eq!(system.index_register(), 0x300);
+    assert_eq!(system.delay_timer(), 0x2A);
+    assert_eq!(system.sound_timer(), 0);
+    assert_eq!(system.memory_slice(0x200..0x202), &[0x60, 0x2A]);
+}
+
+#[test]
+fn test_unknown_opcode_headless() {
+    let mut system = System::headless();
+    system
+        .copy_buffer_to_memory(vec![0xFF, 0xFF], 0x200)
+        .unwrap();
+
+    assert_eq!(
+        system.cycle(),
+        Err(EmulationError::UnknownOpcode {
+            opcode: 0xFFFF,
+            pc: 0x200
+        })
+    );
+}